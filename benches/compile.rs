@@ -0,0 +1,93 @@
+//! Compile time of the optimizers on large generated programs
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use isbfc::lir::CellType;
+use isbfc::{NewOptimizer, OldOptimizer, Optimizer, AST};
+
+/// Deterministic pseudo-random numbers, so every run compiles the same code
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+/// Long runs of adds and shifts, which become large DAGs
+fn straight_line(rng: &mut Lcg, len: usize) -> String {
+    let mut code = String::new();
+    for _ in 0..len {
+        code.push(match rng.next(4) {
+            0 => '+',
+            1 => '-',
+            2 => '>',
+            _ => '<',
+        });
+    }
+    code
+}
+
+/// Balanced loops that move and multiply cells, nested up to *depth*
+fn arithmetic(rng: &mut Lcg, depth: u32) -> String {
+    let mut code = String::new();
+    for _ in 0..rng.next(4) + 1 {
+        if depth > 0 && rng.next(2) == 0 {
+            let body = arithmetic(rng, depth - 1);
+            code.push_str(&format!(">[{}-]<", body));
+        } else {
+            code.push_str(&straight_line(rng, 8));
+            code.push_str(match rng.next(3) {
+                0 => "[->+>++<<]",
+                1 => ">[-]<",
+                _ => ">>[-<<+>>]<<",
+            });
+        }
+    }
+    // Keep the cursor where it started, so loops stay balanced
+    let balance = code.matches('>').count() as isize - code.matches('<').count() as isize;
+    let fix = if balance > 0 { '<' } else { '>' };
+    code.extend(std::iter::repeat_n(fix, balance.unsigned_abs()));
+    code
+}
+
+fn programs() -> Vec<(&'static str, Vec<AST>)> {
+    let mut rng = Lcg(1);
+    let straight = straight_line(&mut rng, 100_000) + ".";
+    let arithmetic = (0..500)
+        .map(|_| arithmetic(&mut rng, 4) + ".")
+        .collect::<String>();
+    // Moves between unknown cells, which all end up in one DAG
+    let transfers = ",".to_string() + &"[->+>++<<]>".repeat(5_000) + ".";
+    // Running sums of many input cells, which keep large values live
+    let sums = ",>".repeat(400) + &"<".repeat(400) + &"[->+<]>".repeat(399) + ".";
+    vec![
+        ("straight_line", isbfc::parse(straight.as_bytes()).unwrap()),
+        ("transfers", isbfc::parse(transfers.as_bytes()).unwrap()),
+        ("arithmetic", isbfc::parse(arithmetic.as_bytes()).unwrap()),
+        ("sums", isbfc::parse(sums.as_bytes()).unwrap()),
+    ]
+}
+
+fn bench_optimizers(c: &mut Criterion) {
+    let new_passes = NewOptimizer.passes_at(2);
+    let old_passes = OldOptimizer.passes_at(2);
+    for (name, ast) in programs() {
+        c.bench_function(&format!("new {}", name), |b| {
+            b.iter(|| NewOptimizer.optimize(&ast, &new_passes, CellType::U64))
+        });
+        c.bench_function(&format!("old {}", name), |b| {
+            b.iter(|| OldOptimizer.optimize(&ast, &old_passes, CellType::U64))
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_optimizers
+}
+criterion_main!(benches);
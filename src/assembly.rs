@@ -1,31 +1,58 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::process::{Command, Stdio};
 
 use crate::elf::{elf64_get_section, elf64_write};
 
-fn object_to_binary(mut o_file: File) -> io::Result<(Vec<u8>, u64)> {
+/// Links an object file to a flat binary, returning the text, read-only
+/// data, and the size of the bss section. Sections are placed at the
+/// addresses `elf64_write` expects.
+fn object_to_binary(o_name: &str) -> io::Result<(Vec<u8>, Vec<u8>, u64)> {
+    let mut o_file = File::open(o_name)?;
     let text = elf64_get_section(&mut o_file, b".text")?.unwrap();
+    let rodata = elf64_get_section(&mut o_file, b".rodata")?;
     let bss = elf64_get_section(&mut o_file, b".bss")?.unwrap();
-    let bss_offset = (text.sh_size + 0x1000 - 1) & !(0x1000 - 1);
+    let rodata_offset = (text.sh_size + 0x1000 - 1) & !(0x1000 - 1);
+    let rodata_size = rodata.map_or(0, |rodata| rodata.sh_size);
+    let bss_offset = rodata_offset + ((rodata_size + 0x1000 - 1) & !(0x1000 - 1));
     let bss_size = bss.sh_size;
 
-    let bin = Command::new("ld")
+    let mut command = Command::new("ld");
+    command
         .arg("--oformat")
         .arg("binary")
         .arg("-Ttext")
-        .arg("0x40_1000")
+        .arg("0x401000");
+    if rodata_size != 0 {
+        command.arg(format!(
+            "--section-start=.rodata=0x{:x}",
+            0x40_1000 + rodata_offset
+        ));
+    }
+    // ld seeks over the gap before rodata, so it can't write to a pipe
+    let bin_name = format!("{}.bin", o_name);
+    let status = command
         .arg("-Tbss")
         .arg(format!("0x{:x}", 0x40_1000 + bss_offset))
         .arg("-o")
-        .arg("/dev/stdout")
-        .arg("/dev/stdin")
-        .stdin(o_file)
-        .output()?
-        .stdout;
+        .arg(&bin_name)
+        .arg(o_name)
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other("ld failed"));
+    }
+    let mut bin = fs::read(&bin_name)?;
+    fs::remove_file(&bin_name)?;
+
+    // The flat binary contains the rodata after padding the text to a page
+    let rodata = if rodata_size != 0 {
+        bin.split_off(rodata_offset as usize)
+    } else {
+        Vec::new()
+    };
 
-    Ok((bin, bss_size))
+    Ok((bin, rodata, bss_size))
 }
 
 pub fn assemble(code: &str, out_name: &str, debug: bool) -> io::Result<Option<i32>> {
@@ -46,12 +73,11 @@ pub fn assemble(code: &str, out_name: &str, debug: bool) -> io::Result<Option<i3
 }
 
 pub fn link(o_name: &str, out_name: &str, minimal: bool) -> io::Result<Option<i32>> {
-    let o_file = File::open(o_name)?;
     if minimal {
-        let (bin, bss_size) = object_to_binary(o_file)?;
+        let (text, rodata, bss_size) = object_to_binary(o_name)?;
 
         let mut file = File::create(out_name)?;
-        elf64_write(&mut file, &bin, bss_size)?;
+        elf64_write(&mut file, &text, &rodata, bss_size)?;
         let mut permissions = file.metadata().unwrap().permissions();
         permissions.set_mode(permissions.mode() | 0o111);
         file.set_permissions(permissions)?;
@@ -61,8 +87,7 @@ pub fn link(o_name: &str, out_name: &str, minimal: bool) -> io::Result<Option<i3
         Ok(Command::new("gcc")
             .arg("-o")
             .arg(out_name)
-            .arg("/dev/stdin")
-            .stdin(o_file)
+            .arg(o_name)
             .spawn()?
             .wait()?
             .code())
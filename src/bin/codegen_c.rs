@@ -7,7 +7,7 @@ fn main() {
     std::io::stdin().read_to_end(&mut code).unwrap();
 
     let ast = isbfc::parse(&code).unwrap();
-    let lir = NewOptimizer.optimize(&ast, 3);
+    let lir = NewOptimizer.optimize(&ast, &NewOptimizer.passes_at(3), CellType::U64);
     let c = codegen(&lir, CellType::U64, 8192);
 
     std::io::stdout().write_all(c.as_bytes()).unwrap();
@@ -1,5 +1,6 @@
 use cranelift_codegen::ir::types::I64;
 use isbfc::codegen::cranelift::codegen_fn;
+use isbfc::lir::CellType;
 use isbfc::{OldOptimizer, Optimizer};
 use std::io::Read;
 
@@ -8,7 +9,7 @@ fn main() {
     std::io::stdin().read_to_end(&mut code).unwrap();
 
     let ast = isbfc::parse(&code).unwrap();
-    let lir = OldOptimizer.optimize(&ast, 3);
+    let lir = OldOptimizer.optimize(&ast, &OldOptimizer.passes_at(3), CellType::U64);
     let func = codegen_fn(&lir, I64, 8192);
 
     println!("{}", func.display());
@@ -1,11 +1,13 @@
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::ops::Range;
 use std::process::{self, Command, Stdio};
 
 use clap::{Arg, ArgAction, ArgGroup};
 
 use isbfc::codegen::c_codegen::{codegen, CellType};
-use isbfc::{Optimizer, OPTIMIZERS};
+use isbfc::lir::OutputBuffering;
+use isbfc::{Dialect, Lint, Optimizer, ParseError, PassConfig, LIR, MAX_LEVEL, OPTIMIZERS};
 
 enum Action {
     Compile,
@@ -13,6 +15,16 @@ enum Action {
     DumpAst,
     DumpIr,
     DumpLir,
+    Lint,
+    Format { minify: bool, line_width: usize },
+    Brainfuck,
+}
+
+/// How parse errors are reported
+enum ErrorFormat {
+    Human,
+    /// One JSON object per error or warning, for editors
+    Json,
 }
 
 struct Options {
@@ -20,10 +32,16 @@ struct Options {
     output: Option<String>,
     input: String,
     tape_size: i32,
-    level: u32,
+    cell: CellType,
+    passes: PassConfig,
     debug: bool,
     minimal_elf: bool,
+    output_buffering: OutputBuffering,
+    partial_eval: Option<usize>,
     optimizer: &'static dyn Optimizer,
+    error_format: ErrorFormat,
+    dialect: Option<Dialect>,
+    extensions: bool,
 }
 
 impl Options {
@@ -56,11 +74,48 @@ impl Options {
                     .action(ArgAction::SetTrue)
                     .help("Dump low level intermediate representation; for debugging"),
             )
-            .group(ArgGroup::new("actions").args(&[
+            .arg(
+                Arg::new("lint")
+                    .long("lint")
+                    .action(ArgAction::SetTrue)
+                    .help("Warn about code that is probably not what was meant"),
+            )
+            .arg(
+                Arg::new("fmt")
+                    .long("fmt")
+                    .action(ArgAction::SetTrue)
+                    .help("Rewrite the code in a canonical layout, keeping comments"),
+            )
+            .arg(
+                Arg::new("minify")
+                    .long("minify")
+                    .action(ArgAction::SetTrue)
+                    .requires("fmt")
+                    .help("With --fmt, strip comments and commands that cancel out"),
+            )
+            .arg(
+                Arg::new("line_width")
+                    .long("line-width")
+                    .value_parser(clap::value_parser!(usize))
+                    .requires("fmt")
+                    .help("Width of lines written by --fmt")
+                    .default_value("80")
+                    .value_name("columns"),
+            )
+            .arg(
+                Arg::new("brainfuck")
+                    .long("brainfuck")
+                    .action(ArgAction::SetTrue)
+                    .help("Write optimized brainfuck, for interpreters with 8 bit cells"),
+            )
+            .group(ArgGroup::new("actions").args([
                 "output_asm",
                 "dump_ast",
                 "dump_ir",
                 "dump_lir",
+                "lint",
+                "fmt",
+                "brainfuck",
             ]))
             .arg(
                 Arg::new("debugging_symbols")
@@ -83,39 +138,183 @@ impl Options {
                     .default_value("8192")
                     .value_name("bytes"),
             )
+            .arg(
+                Arg::new("cell_size")
+                    .long("cell-size")
+                    .value_parser(clap::builder::PossibleValuesParser::new([
+                        "8", "16", "32", "64",
+                    ]))
+                    .help("Size of tape cells")
+                    .default_value("64")
+                    .value_name("bits"),
+            )
             .arg(
                 Arg::new("minimal_elf")
                     .long("minimal-elf")
                     .action(ArgAction::SetTrue)
                     .help("Generate minimal ELF executable"),
             )
+            .arg(
+                Arg::new("output_buffering")
+                    .long("output-buffering")
+                    .value_parser(clap::builder::PossibleValuesParser::new([
+                        "none", "line", "full",
+                    ]))
+                    .help("When generated programs write buffered output")
+                    .default_value("full"),
+            )
+            .arg(
+                Arg::new("partial_eval")
+                    .long("partial-eval")
+                    .action(ArgAction::SetTrue)
+                    .help("Run the program at compile time until it first reads input"),
+            )
+            .arg(
+                Arg::new("partial_eval_steps")
+                    .long("partial-eval-steps")
+                    .value_parser(clap::value_parser!(usize))
+                    .help("Maximum number of steps to run with --partial-eval")
+                    .default_value("1000000")
+                    .value_name("steps"),
+            )
             .arg(
                 Arg::new("optimizer")
                     .long("optimizer")
-                    .value_parser(clap::builder::PossibleValuesParser::new(&OPTIMIZERS.keys().cloned().collect::<Vec<&str>>()))
+                    .value_parser(clap::builder::PossibleValuesParser::new(
+                        OPTIMIZERS.keys().cloned().collect::<Vec<&str>>(),
+                    ))
                     .default_value("new"),
             )
             .arg(
                 Arg::new("level")
                     .short('O')
-                    .value_parser(clap::value_parser!(u32))
+                    .value_parser(clap::value_parser!(u32).range(0..=MAX_LEVEL as i64))
                     .help("Optimization level")
-                    .default_value("1"),
+                    .default_value("2"),
+            )
+            .arg(
+                Arg::new("list_passes")
+                    .long("list-passes")
+                    .action(ArgAction::SetTrue)
+                    .help("List the optimizer's passes and the level each is enabled at"),
+            )
+            .arg(
+                Arg::new("enable_pass")
+                    .long("enable-pass")
+                    .action(ArgAction::Append)
+                    .help("Enable an optimization pass")
+                    .value_name("pass"),
+            )
+            .arg(
+                Arg::new("disable_pass")
+                    .long("disable-pass")
+                    .action(ArgAction::Append)
+                    .help("Disable an optimization pass")
+                    .value_name("pass"),
+            )
+            .arg(
+                Arg::new("dump_after")
+                    .long("dump-after")
+                    .help("Dump the IR to stderr after a pass; for debugging")
+                    .value_name("pass"),
+            )
+            .arg(
+                Arg::new("remarks")
+                    .long("remarks")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("partial_eval")
+                    .help(concat!(
+                        "Report what each loop was optimized to; not with --partial-eval, ",
+                        "which leaves code whose loops don't match those in the source"
+                    )),
+            )
+            .arg(
+                Arg::new("error_format")
+                    .long("error-format")
+                    .value_parser(clap::builder::PossibleValuesParser::new(["human", "json"]))
+                    .help("How to report parse errors and lint warnings")
+                    .default_value("human"),
+            )
+            .arg(
+                Arg::new("dialect")
+                    .long("dialect")
+                    .value_parser(clap::builder::PossibleValuesParser::new(["ook", "blub"]))
+                    .conflicts_with_all(["lint", "fmt", "minify", "line_width"])
+                    .help("Read the code as a brainfuck dialect"),
+            )
+            .arg(
+                Arg::new("dialect_file")
+                    .long("dialect-file")
+                    .conflicts_with_all(["dialect", "lint", "fmt", "minify", "line_width"])
+                    .help("Read the code as the dialect defined in a file")
+                    .value_name("file"),
+            )
+            .arg(
+                Arg::new("extensions")
+                    .long("extensions")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with_all([
+                        "dialect",
+                        "dialect_file",
+                        "lint",
+                        "fmt",
+                        "minify",
+                        "line_width",
+                    ])
+                    .help(
+                        "Accept # to write the tape to stderr, and ! before input for the program",
+                    ),
             )
             .arg(
                 Arg::new("FILENAME")
                     .help("Source file to compile")
-                    .required(true)
+                    .required_unless_present("list_passes")
                     .index(1),
             )
             .get_matches();
 
+        let optimizer = *OPTIMIZERS
+            .get(matches.get_one::<String>("optimizer").unwrap().as_str())
+            .unwrap();
+
+        if matches.get_flag("list_passes") {
+            for pass in optimizer.passes() {
+                println!("{:<16}-O{}  {}", pass.name, pass.level, pass.description);
+            }
+            process::exit(0);
+        }
+
+        let passes = match Self::match_passes(&matches, optimizer) {
+            Ok(passes) => passes,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        };
+
+        let dialect = match Self::match_dialect(&matches) {
+            Ok(dialect) => dialect,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        };
+
         let action = if matches.get_flag("dump_ir") {
             Action::DumpIr
         } else if matches.get_flag("dump_ast") {
             Action::DumpAst
         } else if matches.get_flag("dump_lir") {
             Action::DumpLir
+        } else if matches.get_flag("fmt") {
+            Action::Format {
+                minify: matches.get_flag("minify"),
+                line_width: *matches.get_one::<usize>("line_width").unwrap(),
+            }
+        } else if matches.get_flag("lint") {
+            Action::Lint
+        } else if matches.get_flag("brainfuck") {
+            Action::Brainfuck
         } else if matches.get_flag("output_asm") {
             Action::OutputAssembly
         } else {
@@ -131,13 +330,74 @@ impl Options {
                 .unwrap()
                 .parse::<i32>()
                 .unwrap(),
-            level: *matches.get_one::<u32>("level").unwrap(),
+            cell: matches
+                .get_one::<String>("cell_size")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            passes,
             debug: matches.get_flag("debugging_symbols"),
             minimal_elf: matches.get_flag("minimal_elf"),
-            optimizer: *OPTIMIZERS
-                .get(matches.get_one::<String>("optimizer").unwrap().as_str())
+            output_buffering: matches
+                .get_one::<String>("output_buffering")
+                .unwrap()
+                .parse()
                 .unwrap(),
+            partial_eval: if matches.get_flag("partial_eval") {
+                Some(*matches.get_one::<usize>("partial_eval_steps").unwrap())
+            } else {
+                None
+            },
+            optimizer,
+            error_format: match matches.get_one::<String>("error_format").unwrap().as_str() {
+                "json" => ErrorFormat::Json,
+                _ => ErrorFormat::Human,
+            },
+            dialect,
+            extensions: matches.get_flag("extensions"),
+        }
+    }
+
+    fn match_dialect(matches: &clap::ArgMatches) -> Result<Option<Dialect>, String> {
+        if let Some(path) = matches.get_one::<String>("dialect_file") {
+            let config =
+                std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+            return Dialect::from_config(&config)
+                .map(Some)
+                .map_err(|err| format!("{}: {}", path, err));
         }
+        Ok(matches
+            .get_one::<String>("dialect")
+            .map(|name| match name.as_str() {
+                "ook" => Dialect::ook(),
+                _ => Dialect::blub(),
+            }))
+    }
+
+    fn match_passes(
+        matches: &clap::ArgMatches,
+        optimizer: &dyn Optimizer,
+    ) -> Result<PassConfig, String> {
+        let mut passes = optimizer.passes_at(*matches.get_one::<u32>("level").unwrap());
+        for name in matches
+            .get_many::<String>("enable_pass")
+            .unwrap_or_default()
+        {
+            passes.enable(name)?;
+        }
+        for name in matches
+            .get_many::<String>("disable_pass")
+            .unwrap_or_default()
+        {
+            passes.disable(name)?;
+        }
+        if let Some(name) = matches.get_one::<String>("dump_after") {
+            passes.set_dump_after(name)?;
+        }
+        if matches.get_flag("remarks") {
+            passes.collect_remarks();
+        }
+        Ok(passes)
     }
 
     fn get_output<'a>(&'a self, default: &'a str) -> &'a str {
@@ -152,12 +412,12 @@ impl Options {
         if name == "-" {
             Ok(Box::new(io::stdout()))
         } else {
-            Ok(Box::new(File::create(&name)?))
+            Ok(Box::new(File::create(name)?))
         }
     }
 
     fn compile(&self, lir: Vec<isbfc::lir::LIR>) -> io::Result<String> {
-        let c = codegen(&lir, CellType::U64, self.tape_size);
+        let c = codegen(&lir, self.cell, self.tape_size);
 
         let mut child = Command::new("gcc")
             .arg("-x")
@@ -199,6 +459,68 @@ impl Options {
     }
 }
 
+/// *s* as a JSON string
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// A parse error or lint warning as JSON
+fn diagnostic_json(
+    file: &str,
+    level: &str,
+    (line, column): (usize, usize),
+    span: Range<usize>,
+    code: &str,
+    message: &str,
+) -> String {
+    format!(
+        "{{\"file\":{},\"level\":\"{}\",\"line\":{},\"column\":{},\"span\":{{\"start\":{},\"end\":{}}},\"code\":\"{}\",\"message\":{}}}",
+        json_string(file),
+        level,
+        line,
+        column,
+        span.start,
+        span.end,
+        code,
+        json_string(message),
+    )
+}
+
+fn error_json(file: &str, err: &ParseError) -> String {
+    let (kind, position) = (err.kind(), (err.line(), err.column()));
+    diagnostic_json(
+        file,
+        "error",
+        position,
+        err.span(),
+        kind.code(),
+        &kind.to_string(),
+    )
+}
+
+fn lint_json(file: &str, lint: &Lint) -> String {
+    let (kind, position) = (lint.kind(), (lint.line(), lint.column()));
+    diagnostic_json(
+        file,
+        "warning",
+        position,
+        lint.span(),
+        kind.code(),
+        &kind.to_string(),
+    )
+}
+
 fn main() -> io::Result<()> {
     let options = Options::match_options();
 
@@ -207,15 +529,87 @@ fn main() -> io::Result<()> {
     let mut code = Vec::new();
     file.read_to_end(&mut code)?;
 
-    let ast = match isbfc::parse(&code) {
+    let parsed = match &options.dialect {
+        Some(dialect) => dialect.parse(&code).map(|ast| (ast, None)),
+        None if options.extensions => isbfc::parse_extended(&code),
+        None => isbfc::parse(&code).map(|ast| (ast, None)),
+    };
+    let (ast, input) = match parsed {
         Ok(ast) => ast,
-        Err(err) => {
-            println!("Parsing error: {}", err);
+        Err(errors) => {
+            for err in errors {
+                match options.error_format {
+                    ErrorFormat::Human => eprintln!("{}:{}", options.input, err),
+                    ErrorFormat::Json => eprintln!("{}", error_json(&options.input, &err)),
+                }
+            }
             process::exit(1);
         }
     };
 
-    let lir = options.optimizer.optimize(&ast, options.level);
+    if let Action::Lint = options.action {
+        // Brackets match, since the code was parsed
+        for lint in isbfc::lint(&code).unwrap() {
+            match options.error_format {
+                ErrorFormat::Human => eprintln!("{}:{}", options.input, lint),
+                ErrorFormat::Json => eprintln!("{}", lint_json(&options.input, &lint)),
+            }
+        }
+        return Ok(());
+    }
+
+    if let Action::Format { minify, line_width } = options.action {
+        let output = if minify {
+            isbfc::minify(&code)
+        } else {
+            isbfc::format(&code, line_width)
+        };
+        options.open_output_file("-")?.write_all(&output)?;
+        return Ok(());
+    }
+
+    if let Action::Brainfuck = options.action {
+        let mut outfile = options.open_output_file("-")?;
+        write!(outfile, "{}", isbfc::optimize_to_brainfuck(&ast))?;
+        if let Some(input) = &input {
+            outfile.write_all(b"!")?;
+            outfile.write_all(input)?;
+        } else {
+            writeln!(outfile)?;
+        }
+        return Ok(());
+    }
+
+    let (ast, mut lir) = match options.partial_eval {
+        Some(steps) => {
+            let eval = isbfc::partial_eval(&ast, options.cell, steps);
+            let lir = eval.output_lir();
+            (eval.rest, lir)
+        }
+        None => (ast, Vec::new()),
+    };
+
+    if let Some(input) = input {
+        lir.push(LIR::DeclareInput(input));
+    }
+    lir.extend(
+        options
+            .optimizer
+            .optimize(&ast, &options.passes, options.cell),
+    );
+    let lir = isbfc::lir::buffer_output(&lir, options.output_buffering);
+
+    let positions = match &options.dialect {
+        Some(dialect) => dialect.loop_positions(&code),
+        None => isbfc::loop_positions(&code),
+    };
+    for warning in options.passes.warnings() {
+        eprintln!("warning: {}", warning);
+    }
+    for (id, remark) in options.passes.remarks() {
+        let (line, column) = positions[id];
+        eprintln!("{}:{}:{}: {}", options.input, line, column, remark);
+    }
 
     match options.action {
         Action::DumpAst => {
@@ -226,7 +620,7 @@ fn main() -> io::Result<()> {
             let mut outfile = options.open_output_file("-")?;
             options
                 .optimizer
-                .dumpir(&ast, options.level, &mut outfile)?;
+                .dumpir(&ast, &options.passes, options.cell, &mut outfile)?;
         }
         Action::DumpLir => {
             let mut outfile = options.open_output_file("-")?;
@@ -245,9 +639,32 @@ fn main() -> io::Result<()> {
             println!("Compiling...");
             let output = options.compile(lir)?;
             let out_name = options.get_output(name);
-            options.asm_and_link(&output, &name, &out_name);
+            options.asm_and_link(&output, name, out_name);
         }
+        Action::Lint | Action::Format { .. } | Action::Brainfuck => unreachable!(),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_as_json() {
+        let errors = isbfc::parse(b"+\n\"]\"").unwrap_err();
+        assert_eq!(
+            error_json("dir\\a \"b\".bf", &errors[0]),
+            concat!(
+                r#"{"file":"dir\\a \"b\".bf","level":"error","line":2,"column":2,"#,
+                r#""span":{"start":3,"end":4},"code":"E002","message":"] found when not in a loop"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn json_control_characters() {
+        assert_eq!(json_string("a\nb\tc\u{1}"), r#""a\nb\u0009c\u0001""#);
+    }
+}
@@ -2,8 +2,9 @@ use cranelift_codegen::ir::types::I64;
 use cranelift_codegen::ir::Signature;
 use cranelift_codegen::isa::CallConv;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{DataDescription, DataId, FuncId, Module};
+use cranelift_module::{DataDescription, Module};
 use isbfc::codegen::cranelift::codegen_fn;
+use isbfc::lir::CellType;
 use isbfc::{OldOptimizer, Optimizer};
 use std::io::Read;
 
@@ -13,7 +14,7 @@ fn main() {
     std::io::stdin().read_to_end(&mut code).unwrap();
 
     let ast = isbfc::parse(&code).unwrap();
-    let lir = OldOptimizer.optimize(&ast, 3);
+    let lir = OldOptimizer.optimize(&ast, &OldOptimizer.passes_at(3), CellType::U64);
     let mut func = codegen_fn(&lir, I64, 8192);
 
     let builder = JITBuilder::new(cranelift_module::default_libcall_names()).unwrap();
@@ -38,13 +39,14 @@ fn main() {
     // TODO populate context
     if let Err(err) = module.define_function(func_id, &mut context) {
         eprintln!("{}", err);
-        if let cranelift_module::ModuleError::Compilation(err) = &err {
-            if let cranelift_codegen::CodegenError::Verifier(errs) = err {
-                for err in &errs.0 {
-                    eprintln!("{}", err);
-                }
-                std::process::exit(1);
+        if let cranelift_module::ModuleError::Compilation(
+            cranelift_codegen::CodegenError::Verifier(errs),
+        ) = &err
+        {
+            for err in &errs.0 {
+                eprintln!("{}", err);
             }
+            std::process::exit(1);
         }
         eprintln!("{:?}", err);
         std::process::exit(1);
@@ -0,0 +1,209 @@
+//! Brainfuck written back from optimized tokens.
+//!
+//! Tokens the optimizer produces from counter loops, `MulCopy` and `If`, are
+//! always followed by clearing the loop's cell, so they are written as
+//! loops that clear it. Constant output and ifs from other loops can't be
+//! written as brainfuck, so those passes are disabled.
+
+use std::collections::{BTreeMap, HashSet};
+use std::iter::{self, Peekable};
+use std::mem;
+use std::slice;
+
+use crate::lir::CellType;
+use crate::optimizer::old::token::Token;
+use crate::optimizer::old::{optimize_tokens, PASSES};
+use crate::optimizer::{PassConfig, MAX_LEVEL};
+use crate::AST;
+
+#[derive(Default)]
+struct Emitter {
+    code: String,
+    /// Cell the code written so far ends at, relative to the cursor of the
+    /// tokens, which moves without writing anything
+    at: i32,
+    /// What has to be added to cells, by offset, for them to have the value
+    /// the tokens give them. Output is written by adding to the cell and
+    /// leaving the difference here, to be merged into later adds.
+    owed: BTreeMap<i32, i64>,
+    /// Offsets of cells the tokens have left zero
+    zeros: HashSet<i32>,
+}
+
+impl Emitter {
+    /// Moves to the cell at *offset*
+    fn go(&mut self, offset: i32) {
+        let c = if offset > self.at { '>' } else { '<' };
+        let count = (offset - self.at).unsigned_abs() as usize;
+        self.code.extend(iter::repeat_n(c, count));
+        self.at = offset;
+    }
+
+    /// Adds *value* to the cell at *offset*, along with anything owed to it
+    fn add(&mut self, offset: i32, value: i64) {
+        let value = CellType::U8.wrap(value.wrapping_add(self.owed.remove(&offset).unwrap_or(0)));
+        if value != 0 {
+            self.go(offset);
+            let c = if value > 0 { '+' } else { '-' };
+            self.code
+                .extend(iter::repeat_n(c, value.unsigned_abs() as usize));
+        }
+    }
+
+    /// Adds everything owed to cells, before code that reads them
+    fn settle(&mut self) {
+        for (offset, value) in mem::take(&mut self.owed) {
+            self.add(offset, value);
+        }
+    }
+
+    /// Writes `[`, at the cell at *offset*
+    fn open(&mut self, offset: i32) {
+        self.settle();
+        self.go(offset);
+        self.code.push('[');
+        self.zeros.clear();
+    }
+
+    /// Writes `]`, at the cell at *offset*, which is then zero
+    fn close(&mut self, offset: i32) {
+        self.settle();
+        self.go(offset);
+        self.code.push(']');
+        self.zeros.clear();
+        self.zeros.insert(offset);
+    }
+}
+
+/// Optimizes *ast*, and writes it back as brainfuck for interpreters with
+/// 8 bit cells
+///
+/// # Examples
+/// ```
+/// let ast = isbfc::parse(b"+-[+]>><,[->+<]>.").unwrap();
+/// assert_eq!(isbfc::optimize_to_brainfuck(&ast), "[-]>,[->+<]>.");
+/// ```
+pub fn optimize_to_brainfuck(ast: &[AST]) -> String {
+    let mut passes = PassConfig::new(PASSES, MAX_LEVEL);
+    passes.disable("const-output").unwrap();
+    passes.disable("if-loops").unwrap();
+    let tokens = optimize_tokens(ast, &passes, CellType::U8);
+
+    let mut bf = Emitter::default();
+    // Loops and ifs being written, innermost last: the rest of the tokens
+    // around each, and for ifs the offset of the cell to clear
+    let mut blocks: Vec<(Peekable<slice::Iter<Token>>, Option<i32>)> = Vec::new();
+
+    let mut rest = tokens.iter().peekable();
+    loop {
+        let token = match rest.next() {
+            Some(token) => token,
+            None => match blocks.pop() {
+                Some((outer_rest, clear)) => {
+                    rest = outer_rest;
+                    match clear {
+                        Some(offset) => {
+                            if !bf.zeros.contains(&offset) {
+                                bf.owed.remove(&offset);
+                                bf.go(offset);
+                                bf.code.push_str("[-]");
+                            }
+                            bf.close(offset);
+                        }
+                        None => bf.close(0),
+                    }
+                    continue;
+                }
+                // What is left in cells at the end can't be seen
+                None => return bf.code,
+            },
+        };
+
+        match *token {
+            Token::Output => {}
+            Token::Input => {
+                bf.owed.remove(&0);
+                bf.settle();
+                bf.go(0);
+                bf.code.push(',');
+                bf.zeros.remove(&0);
+            }
+            Token::Debug => {
+                bf.settle();
+                bf.go(0);
+                bf.code.push('#');
+            }
+            Token::Loop(_, ref contents) => {
+                bf.open(0);
+                blocks.push((mem::replace(&mut rest, contents.iter().peekable()), None));
+            }
+            Token::If(offset, ref contents) => {
+                bf.open(offset);
+                let outer_rest = mem::replace(&mut rest, contents.iter().peekable());
+                blocks.push((outer_rest, Some(offset)));
+            }
+            Token::Move(offset) => {
+                bf.at -= offset;
+                bf.owed = mem::take(&mut bf.owed)
+                    .into_iter()
+                    .map(|(cell, value)| (cell - offset, value))
+                    .collect();
+                bf.zeros = bf.zeros.iter().map(|cell| cell - offset).collect();
+            }
+            Token::Add(offset, value) => {
+                bf.add(offset, value);
+                bf.zeros.remove(&offset);
+            }
+            Token::Set(offset, value) => {
+                if !bf.zeros.contains(&offset) {
+                    bf.owed.remove(&offset);
+                    bf.go(offset);
+                    bf.code.push_str("[-]");
+                }
+                bf.add(offset, value);
+                if CellType::U8.wrap(value) == 0 {
+                    bf.zeros.insert(offset);
+                } else {
+                    bf.zeros.remove(&offset);
+                }
+            }
+            Token::MulCopy(src, dest, mul) => {
+                // Every copy from *src* in a row becomes one loop
+                let mut copies = vec![(dest, mul)];
+                while let Some(Token::MulCopy(next_src, dest, mul)) = rest.peek() {
+                    if *next_src != src {
+                        break;
+                    }
+                    copies.push((*dest, *mul));
+                    rest.next();
+                }
+                if bf.zeros.contains(&src) {
+                    continue;
+                }
+                bf.settle();
+                bf.go(src);
+                bf.code.push_str("[-");
+                for (dest, mul) in copies {
+                    bf.add(dest, mul);
+                    bf.zeros.remove(&dest);
+                }
+                bf.go(src);
+                bf.code.push(']');
+                bf.zeros.insert(src);
+            }
+            Token::Scan(offset) => {
+                bf.open(0);
+                bf.go(offset);
+                bf.at = 0;
+                bf.close(0);
+            }
+            Token::LoadOut(offset, add) => {
+                bf.add(offset, add);
+                bf.go(offset);
+                bf.code.push('.');
+                bf.owed.insert(offset, -add);
+            }
+            Token::LoadOutSet(_) => unreachable!("const-output pass is disabled"),
+        }
+    }
+}
@@ -1,15 +1,16 @@
-use crate::lir::{LVal, RVal, LIR};
+use crate::lir::{CowStr, LVal, OutputBuffering, RVal, LIR, OUTPUT_BUFFER_SIZE};
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::mem;
 use LIR::*;
 
-#[derive(Clone, Copy)]
-pub enum CellType {
-    U8,
-    U16,
-    U32,
-    U64,
-}
+/// How many cells a strided scan checks per iteration
+const SCAN_UNROLL: i32 = 4;
+
+/// How many cells on each side of the cursor `DebugDump` writes
+const DEBUG_DUMP_CELLS: i32 = 8;
+
+pub use crate::lir::CellType;
 
 impl CellType {
     fn c_name(self) -> &'static str {
@@ -35,22 +36,55 @@ fn rval_to_c(val: &RVal) -> String {
         RVal::Reg(reg) => format!("r{}", reg),
         RVal::Tape(offset) => format!("tape[cursor + {}]", offset),
         RVal::Buf(buf, offset) => format!("{}[{}]", buf, offset),
-        RVal::Immediate(value) => format!("{}", value),
+        RVal::Immediate(value) => match i32::try_from(*value) {
+            Ok(value) => format!("{}", value),
+            // Too large for an `int` literal; give the bits explicitly
+            Err(_) => format!("0x{:x}ULL", *value as u64),
+        },
     }
 }
 
-pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
-    let mut output = String::new();
+/// Declarations collected while generating code, which must be emitted
+/// outside of `main` or at its start
+#[derive(Default)]
+struct Declarations<'a> {
+    bss_bufs: HashMap<&'a CowStr, usize>,
+    rodata_bufs: Vec<(&'a CowStr, &'a [u8])>,
+    buffering: Option<OutputBuffering>,
+    input: Option<&'a [u8]>,
+    /// Whether the `debug_dump` function is used
+    debug_dump: bool,
+}
+
+fn codegen_iter<'a>(
+    output: &mut String,
+    decls: &mut Declarations<'a>,
+    lir: &'a [LIR],
+    cell: CellType,
+) {
+    // Rest of the LIR around each block being generated, innermost last
+    let mut blocks = Vec::new();
+    let mut rest = lir.iter();
 
     macro_rules! push_asm {
-        ($($arg:tt)*) => {
+        ($($arg:tt)*) => {{
+            output.push_str(&"    ".repeat(blocks.len() + 1));
             (writeln!(output, $($arg)*)).unwrap()
-        };
+        }};
     }
 
-    let mut bss_bufs = HashMap::new();
-
-    for i in lir {
+    loop {
+        let i = match rest.next() {
+            Some(i) => i,
+            None => match blocks.pop() {
+                Some(outer_rest) => {
+                    rest = outer_rest;
+                    push_asm!("}}");
+                    continue;
+                }
+                None => return,
+            },
+        };
         match i {
             Shift(shift) => push_asm!("cursor += {};", shift),
             Mul(dest, a, b) => push_asm!(
@@ -72,6 +106,13 @@ pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
                 rval_to_c(b)
             ),
             Mov(dest, src) => push_asm!("{} = {};", lval_to_c(dest, cell), rval_to_c(src)),
+            Select(dest, cond, a, b) => push_asm!(
+                "{} = {} != 0 ? {} : {};",
+                lval_to_c(dest, cell),
+                rval_to_c(cond),
+                rval_to_c(a),
+                rval_to_c(b)
+            ),
             // https://stackoverflow.com/questions/18496282/why-do-i-get-a-label-can-only-be-part-of-a-statement-and-a-declaration-is-not-a
             Label(label) => push_asm!("{}: ;", label),
             Jp(label) => push_asm!("goto {};", label),
@@ -81,8 +122,42 @@ pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
             Jnz(comparand, label) => {
                 push_asm!("if ({} != 0) {{ goto {}; }}", rval_to_c(comparand), label)
             }
+            Scan(stride) => match (cell, stride) {
+                // Cells are bytes, so libc's optimized search can be used
+                (CellType::U8, 1) => push_asm!(
+                    "cursor = (uint8_t *)memchr(tape + cursor, 0, sizeof(tape) - cursor) - tape;"
+                ),
+                (CellType::U8, -1) => {
+                    push_asm!("cursor = (uint8_t *)memrchr(tape, 0, cursor + 1) - tape;")
+                }
+                _ => {
+                    push_asm!("while (tape[cursor] != 0) {{");
+                    for i in 1..SCAN_UNROLL {
+                        push_asm!(
+                            "    if (tape[cursor + {0}] == 0) {{ cursor += {0}; break; }}",
+                            i * stride
+                        );
+                    }
+                    push_asm!("    cursor += {};", SCAN_UNROLL * stride);
+                    push_asm!("}}");
+                }
+            },
+            Loop { cond, body } => {
+                push_asm!("while ({} != 0) {{", rval_to_c(cond));
+                blocks.push(mem::replace(&mut rest, body.iter()));
+            }
+            If { cond, body } => {
+                push_asm!("if ({} != 0) {{", rval_to_c(cond));
+                blocks.push(mem::replace(&mut rest, body.iter()));
+            }
             DeclareBssBuf(buffer, len) => {
-                bss_bufs.insert(buffer, len);
+                decls.bss_bufs.insert(buffer, *len);
+            }
+            DeclareRodataBuf(buffer, data) => {
+                decls.rodata_bufs.push((buffer, data));
+            }
+            DeclareOutputBuffering(buffering) => {
+                decls.buffering = Some(*buffering);
             }
             Input(buffer, offset, len) => {
                 push_asm!("fread({}+{}, 1, {}, stdin);", buffer, offset, len)
@@ -90,22 +165,110 @@ pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
             Output(buffer, offset, len) => {
                 push_asm!("fwrite({}+{}, 1, {}, stdout);", buffer, offset, len)
             }
+            Flush => push_asm!("fflush(stdout);"),
+            DebugDump(offset) => {
+                decls.debug_dump = true;
+                push_asm!("debug_dump(cursor + {});", offset);
+            }
+            DeclareInput(data) => {
+                decls.input = Some(data);
+            }
         }
     }
+}
+
+pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
+    let mut output = String::new();
+    let mut decls = Declarations::default();
+    codegen_iter(&mut output, &mut decls, lir, cell);
 
     let mut bss = String::new();
-    for (name, len) in bss_bufs {
+    for (name, len) in decls.bss_bufs {
         writeln!(bss, "char {}[{}];", name, len).unwrap();
     }
+    for (name, data) in decls.rodata_bufs {
+        let data = data.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        writeln!(
+            bss,
+            "static const char {}[{}] = {{{}}};",
+            name,
+            data.len(),
+            data.join(", ")
+        )
+        .unwrap();
+    }
+
+    if let Some(data) = decls.input {
+        // With a zero after it, so the array is never empty
+        let data = data
+            .iter()
+            .chain(&[0])
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        writeln!(
+            bss,
+            "static const char embedded_input[] = {{{}}};",
+            data.join(", ")
+        )
+        .unwrap();
+    }
+    if decls.debug_dump {
+        // Cells are numbered from where the cursor starts
+        write!(
+            bss,
+            concat!(
+                "static void debug_dump(ssize_t cell) {{\n",
+                "    ssize_t start = cell < {0} ? 0 : cell - {0};\n",
+                "    ssize_t end = cell + {0} >= {1} ? {1} - 1 : cell + {0};\n",
+                "    fprintf(stderr, \"#%zd:\", cell - {2});\n",
+                "    for (ssize_t i = start; i <= end; i++) {{\n",
+                "        fprintf(stderr, i == cell ? \" [%llu]\" : \" %llu\", (unsigned long long)tape[i]);\n",
+                "    }}\n",
+                "    fputc('\\n', stderr);\n",
+                "}}\n",
+            ),
+            DEBUG_DUMP_CELLS,
+            tape_size,
+            tape_size / 2
+        )
+        .unwrap();
+    }
+    // Read from the embedded input rather than stdin
+    let stdin = match decls.input {
+        Some(data) => format!(
+            "    stdin = fmemopen((void *)embedded_input, {}, \"r\");\n",
+            data.len()
+        ),
+        None => String::new(),
+    };
+
+    // stdio flushes a fully buffered stream only when its buffer fills,
+    // which makes it a suitable runtime for explicit `Flush`
+    let setvbuf = match decls.buffering {
+        Some(OutputBuffering::None) => "    setvbuf(stdout, NULL, _IONBF, 0);\n".to_string(),
+        Some(OutputBuffering::Line) => format!(
+            "    setvbuf(stdout, NULL, _IOLBF, {});\n",
+            OUTPUT_BUFFER_SIZE
+        ),
+        Some(OutputBuffering::Full) => format!(
+            "    setvbuf(stdout, NULL, _IOFBF, {});\n",
+            OUTPUT_BUFFER_SIZE
+        ),
+        None => String::new(),
+    };
 
-    return format!(
+    format!(
         concat!(
+            "#define _GNU_SOURCE\n",
             "#include <stdint.h>\n",
             "#include <stdio.h>\n",
+            "#include <string.h>\n",
             "{} tape[{}];\n",
             "ssize_t cursor = {};\n",
             "{}\n",
             "int main() {{\n",
+            "{}",
+            "{}",
             "{}\n",
             "}}\n"
         ),
@@ -113,6 +276,8 @@ pub fn codegen(lir: &[LIR], cell: CellType, tape_size: i32) -> String {
         tape_size,
         tape_size / 2,
         bss,
+        stdin,
+        setvbuf,
         output
-    );
+    )
 }
@@ -1,8 +1,8 @@
 // XXX: WIP, not usable
 
-#![allow(dead_code, unused_variables)]
+#![allow(dead_code, unused_variables, clippy::upper_case_acronyms)]
 
-use crate::lir::{CowStr, LIR};
+use crate::lir::{self, CowStr, LIR};
 use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter, Write};
 
@@ -114,7 +114,11 @@ fn val_to_asm(val: impl Into<RVal>) -> String {
 }
 */
 
-fn lir_to_instrs(lir: &[LIR], bss_bufs: &mut HashMap<CowStr, usize>) -> Vec<Instr> {
+fn lir_to_instrs(
+    lir: &[LIR],
+    bss_bufs: &mut HashMap<CowStr, usize>,
+    rodata_bufs: &mut HashMap<CowStr, Vec<u8>>,
+) -> Vec<Instr> {
     use LIR::*;
 
     let mut instrs = Vec::new();
@@ -134,6 +138,7 @@ fn lir_to_instrs(lir: &[LIR], bss_bufs: &mut HashMap<CowStr, usize>) -> Vec<Inst
             }
             Sub(dest, a, b) => {}
             Mov(dest, src) => {}
+            Select(dest, cond, a, b) => {}
             Label(label) => instrs.push(Instr::Label(label.clone())),
             Jp(label) => instrs.push(Instr::Jmp(label.clone())),
             Jz(comparand, label) => {
@@ -147,8 +152,17 @@ fn lir_to_instrs(lir: &[LIR], bss_bufs: &mut HashMap<CowStr, usize>) -> Vec<Inst
             DeclareBssBuf(buffer, len) => {
                 bss_bufs.insert(buffer.clone(), *len);
             }
+            DeclareRodataBuf(buffer, data) => {
+                rodata_bufs.insert(buffer.clone(), data.clone());
+            }
             Input(buffer, offset, len) => {}
             Output(buffer, offset, len) => {}
+            DeclareOutputBuffering(buffering) => {}
+            Flush => {}
+            DebugDump(offset) => {}
+            DeclareInput(data) => {}
+            Scan(stride) => {}
+            Loop { .. } | If { .. } => unreachable!("structured control flow should be flattened"),
         }
     }
 
@@ -158,15 +172,22 @@ fn lir_to_instrs(lir: &[LIR], bss_bufs: &mut HashMap<CowStr, usize>) -> Vec<Inst
 fn codegen(lir: &[LIR]) -> String {
     let mut output = String::new();
     let mut bss_bufs = HashMap::new();
+    let mut rodata_bufs = HashMap::new();
 
     writeln!(output, ".section .text").unwrap();
     writeln!(output, ".global _start").unwrap();
     writeln!(output, "_start:").unwrap();
 
-    for i in lir_to_instrs(lir, &mut bss_bufs) {
+    for i in lir_to_instrs(&lir::flatten(lir), &mut bss_bufs, &mut rodata_bufs) {
         writeln!(output, "{}", i).unwrap();
     }
 
+    writeln!(output, ".section .rodata").unwrap();
+    for (name, data) in &rodata_bufs {
+        let data = data.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        writeln!(output, "{}: .byte {}", name, data.join(", ")).unwrap();
+    }
+
     writeln!(output, ".section .bss").unwrap();
     for (name, len) in &bss_bufs {
         writeln!(output, ".lcomm {}, {}", name, len).unwrap();
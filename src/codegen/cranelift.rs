@@ -1,10 +1,10 @@
 use crate::lir::{CowStr, LVal, RVal, LIR};
 use std::collections::HashMap;
+use std::mem;
 
 use cranelift::prelude::*;
 use cranelift_codegen::cursor::FuncCursor;
 use cranelift_codegen::ir::function::Function;
-use cranelift_codegen::ir::immediates::Offset32;
 use cranelift_codegen::ir::InstBuilder;
 use cranelift_control::ControlPlane;
 
@@ -82,7 +82,7 @@ impl Codegen {
                     *offset as i32 * self.cell_type.bytes() as i32,
                 )
             }
-            RVal::Immediate(value) => builder.ins().iconst(self.cell_type, i64::from(*value)),
+            RVal::Immediate(value) => builder.ins().iconst(self.cell_type, *value),
         }
     }
 
@@ -109,78 +109,159 @@ impl Codegen {
         self.store(builder, lval, res);
     }
 
-    fn instr(&mut self, builder: &mut FunctionBuilder, lir: &LIR) {
-        match lir {
-            LIR::Shift(offset) => {
-                let mut tape_cursor = builder.use_var(self.tape_cursor);
-                let offset = builder.ins().iconst(types::I32, i64::from(*offset));
-                tape_cursor = builder.ins().sadd_overflow(tape_cursor, offset).0;
-                builder.def_var(self.tape_cursor, tape_cursor);
-            }
-            LIR::Mul(res_ptr, lhs, rhs) => {
-                self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
-                    cursor.ins().imul(lhs, rhs)
-                })
-            }
-            LIR::Add(res_ptr, lhs, rhs) => {
-                self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
-                    cursor.ins().iadd(lhs, rhs)
-                })
-            }
-            LIR::Sub(res_ptr, lhs, rhs) => {
-                self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
-                    cursor.ins().isub(lhs, rhs)
-                })
-            }
-            LIR::Mov(dst, src) => {
-                let src = self.rval_to_cl(builder, &src);
-                self.store(builder, dst, src);
-            }
-            LIR::Label(label) => {
-                // TODO seal current block
-                let block = self.block(builder, label);
-                // Jump to next block; XXX if not ended on jump?
-                builder.ins().jump(block, &[]);
-                builder.switch_to_block(block);
-            }
-            LIR::Jp(label) => {
-                let block = self.block(builder, label);
-                builder.ins().jump(block, &[]);
-                // XXX make sure block is terminated?
-
-                // XXX
-                let next_block = builder.create_block();
-                builder.switch_to_block(next_block);
-            }
-            LIR::Jz(comparand, label) => {
-                let block = self.block(builder, label);
-                let else_block = builder.create_block(); // XXX continue? Add block?
-                let value = self.rval_to_cl(builder, &comparand);
-                let value = builder.ins().bnot(value);
-                builder.ins().brif(value, block, &[], else_block, &[]);
-                builder.switch_to_block(else_block);
-            }
-            LIR::Jnz(comparand, label) => {
-                let block = self.block(builder, label);
-                let else_block = builder.create_block(); // XXX continue? Add block?
-                let value = self.rval_to_cl(builder, &comparand);
-                builder.ins().brif(value, block, &[], else_block, &[]);
-                builder.switch_to_block(else_block);
-            }
-            LIR::DeclareBssBuf(buffer, len) => {
-                // TODO
-            }
-            LIR::Input(buffer, offset, len) => {
-                // TODO
-            }
-            LIR::Output(buffer, offset, len) => {
-                // TODO
+    fn shift(&mut self, builder: &mut FunctionBuilder, offset: i32) {
+        let mut tape_cursor = builder.use_var(self.tape_cursor);
+        let offset = builder.ins().iconst(types::I32, i64::from(offset));
+        tape_cursor = builder.ins().sadd_overflow(tape_cursor, offset).0;
+        builder.def_var(self.tape_cursor, tape_cursor);
+    }
+
+    /// Starts a loop on *cond*, returning its header block, which checks
+    /// *cond*, and the block after the loop. Code generated next goes in
+    /// the loop body.
+    fn loop_start(&mut self, builder: &mut FunctionBuilder, cond: &RVal) -> (Block, Block) {
+        let header_block = builder.create_block();
+        let body_block = builder.create_block();
+        let exit_block = builder.create_block();
+        builder.ins().jump(header_block, &[]);
+
+        builder.switch_to_block(header_block);
+        let value = self.rval_to_cl(builder, cond);
+        builder.ins().brif(value, body_block, &[], exit_block, &[]);
+
+        builder.switch_to_block(body_block);
+        (header_block, exit_block)
+    }
+
+    /// Generates *lir*. Loop and if bodies are generated on an explicit
+    /// stack, rather than recursively, so deeply nested programs can't
+    /// overflow the stack.
+    fn instrs(&mut self, builder: &mut FunctionBuilder, lir: &[LIR]) {
+        // Rest of the LIR around each body being generated, innermost
+        // last, with the block to jump to at the end of the body and the
+        // block to continue in after it
+        let mut bodies = Vec::new();
+        let mut rest = lir.iter();
+
+        loop {
+            let i = match rest.next() {
+                Some(i) => i,
+                None => match bodies.pop() {
+                    Some((outer_rest, end_block, exit_block)) => {
+                        builder.ins().jump(end_block, &[]);
+                        builder.switch_to_block(exit_block);
+                        rest = outer_rest;
+                        continue;
+                    }
+                    None => return,
+                },
+            };
+            match i {
+                LIR::Shift(offset) => self.shift(builder, *offset),
+                LIR::Mul(res_ptr, lhs, rhs) => {
+                    self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
+                        cursor.ins().imul(lhs, rhs)
+                    })
+                }
+                LIR::Add(res_ptr, lhs, rhs) => {
+                    self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
+                        cursor.ins().iadd(lhs, rhs)
+                    })
+                }
+                LIR::Sub(res_ptr, lhs, rhs) => {
+                    self.binary_op(builder, res_ptr, lhs, rhs, |cursor, lhs, rhs| {
+                        cursor.ins().isub(lhs, rhs)
+                    })
+                }
+                LIR::Mov(dst, src) => {
+                    let src = self.rval_to_cl(builder, src);
+                    self.store(builder, dst, src);
+                }
+                LIR::Select(dest, cond, a, b) => {
+                    let cond = self.rval_to_cl(builder, cond);
+                    let a = self.rval_to_cl(builder, a);
+                    let b = self.rval_to_cl(builder, b);
+                    let res = builder.ins().select(cond, a, b);
+                    self.store(builder, dest, res);
+                }
+                LIR::Label(label) => {
+                    // TODO seal current block
+                    let block = self.block(builder, label);
+                    // Jump to next block; XXX if not ended on jump?
+                    builder.ins().jump(block, &[]);
+                    builder.switch_to_block(block);
+                }
+                LIR::Jp(label) => {
+                    let block = self.block(builder, label);
+                    builder.ins().jump(block, &[]);
+                    // XXX make sure block is terminated?
+
+                    // XXX
+                    let next_block = builder.create_block();
+                    builder.switch_to_block(next_block);
+                }
+                LIR::Jz(comparand, label) => {
+                    let block = self.block(builder, label);
+                    let else_block = builder.create_block(); // XXX continue? Add block?
+                    let value = self.rval_to_cl(builder, comparand);
+                    let value = builder.ins().bnot(value);
+                    builder.ins().brif(value, block, &[], else_block, &[]);
+                    builder.switch_to_block(else_block);
+                }
+                LIR::Jnz(comparand, label) => {
+                    let block = self.block(builder, label);
+                    let else_block = builder.create_block(); // XXX continue? Add block?
+                    let value = self.rval_to_cl(builder, comparand);
+                    builder.ins().brif(value, block, &[], else_block, &[]);
+                    builder.switch_to_block(else_block);
+                }
+                LIR::Loop { cond, body } => {
+                    let (header_block, exit_block) = self.loop_start(builder, cond);
+                    bodies.push((
+                        mem::replace(&mut rest, body.iter()),
+                        header_block,
+                        exit_block,
+                    ));
+                }
+                LIR::Scan(stride) => {
+                    let (header_block, exit_block) = self.loop_start(builder, &RVal::Tape(0));
+                    self.shift(builder, *stride);
+                    builder.ins().jump(header_block, &[]);
+                    builder.switch_to_block(exit_block);
+                }
+                LIR::If { cond, body } => {
+                    let then_block = builder.create_block();
+                    let exit_block = builder.create_block();
+                    let value = self.rval_to_cl(builder, cond);
+                    builder.ins().brif(value, then_block, &[], exit_block, &[]);
+
+                    builder.switch_to_block(then_block);
+                    bodies.push((mem::replace(&mut rest, body.iter()), exit_block, exit_block));
+                }
+                LIR::DeclareBssBuf(..) => {
+                    // TODO
+                }
+                LIR::DeclareRodataBuf(..) => {
+                    // TODO
+                }
+                LIR::Input(..) => {
+                    // TODO
+                }
+                LIR::Output(..) => {
+                    // TODO
+                }
+                LIR::DeclareOutputBuffering(..) | LIR::Flush => {
+                    // TODO
+                }
+                LIR::DebugDump(..) | LIR::DeclareInput(..) => {
+                    // TODO
+                }
             }
         }
     }
 }
 
-pub fn codegen_fn(lir: &[LIR], cell_type: Type, tape_size: i32) -> Function {
+pub fn codegen_fn(lir: &[LIR], cell_type: Type, _tape_size: i32) -> Function {
     let mut func = Function::new();
     let mut context = FunctionBuilderContext::new();
     let mut builder = FunctionBuilder::new(&mut func, &mut context);
@@ -195,9 +276,7 @@ pub fn codegen_fn(lir: &[LIR], cell_type: Type, tape_size: i32) -> Function {
     // TODO
     let mut codegen = Codegen::new(cell_type, tape_ptr, tape_var);
 
-    for i in lir {
-        codegen.instr(&mut builder, i);
-    }
+    codegen.instrs(&mut builder, lir);
     builder.ins().return_(&[]);
 
     builder.seal_all_blocks();
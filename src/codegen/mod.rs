@@ -1,3 +1,5 @@
+pub mod brainfuck;
 pub mod c_codegen;
+#[allow(clippy::module_inception)]
 mod codegen;
 pub mod cranelift;
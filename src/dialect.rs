@@ -0,0 +1,241 @@
+//! Brainfuck dialects that only spell the commands differently.
+//!
+//! A dialect maps tokens to the commands they stand for, and its code is
+//! translated to brainfuck before it is parsed. Tokens are one or more
+//! words, which may be separated by any amount of whitespace, and anything
+//! that isn't a token is a comment.
+//!
+//! Dialects are defined by a config with a line for each token: the command
+//! it stands for, then whitespace, then the token. Lines that are empty or
+//! start with `;` are ignored.
+//!
+//! ```text
+//! ; Ook!
+//! > Ook. Ook?
+//! < Ook? Ook.
+//! ```
+
+use std::ops::Range;
+
+use crate::parser::{column, find_line};
+use crate::{ParseError, AST};
+
+const OOK: &str = "\
+> Ook. Ook?
+< Ook? Ook.
++ Ook. Ook.
+- Ook! Ook!
+. Ook! Ook.
+, Ook. Ook!
+[ Ook! Ook?
+] Ook? Ook!
+";
+
+/// A set of tokens standing for brainfuck commands
+#[derive(Clone, Debug)]
+pub struct Dialect {
+    /// Words of each token, and the command it stands for, longest first
+    tokens: Vec<(Vec<Vec<u8>>, u8)>,
+}
+
+impl Dialect {
+    /// Parses a dialect from *config*, in the format described in the
+    /// module documentation
+    ///
+    /// # Examples
+    /// ```
+    /// let dialect = isbfc::Dialect::from_config("+ plus\n- minus\n. say it").unwrap();
+    /// assert_eq!(dialect.translate(b"plus plus, minus. say  it").0, b"++-.");
+    /// ```
+    pub fn from_config(config: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for (linenum, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap();
+            if command.len() != 1 || !b"+-<>[].,".contains(&command.as_bytes()[0]) {
+                return Err(format!(
+                    "line {}: '{}' is not a brainfuck command",
+                    linenum + 1,
+                    command
+                ));
+            }
+            let words = words
+                .map(|word| word.as_bytes().to_vec())
+                .collect::<Vec<_>>();
+            if words.is_empty() {
+                return Err(format!("line {}: no token for '{}'", linenum + 1, command));
+            }
+            if tokens.iter().any(|(token, _)| *token == words) {
+                return Err(format!(
+                    "line {}: token '{}' is already defined",
+                    linenum + 1,
+                    line[1..].trim()
+                ));
+            }
+            tokens.push((words, command.as_bytes()[0]));
+        }
+
+        // So a token is never read as a shorter one it starts with
+        tokens.sort_by_key(|(words, _)| {
+            let len: usize = words.iter().map(Vec::len).sum();
+            usize::MAX - len
+        });
+        Ok(Self { tokens })
+    }
+
+    /// Ook!, by David Morgan-Mar
+    pub fn ook() -> Self {
+        Self::from_config(OOK).unwrap()
+    }
+
+    /// Blub, which is Ook! with `Blub` for `Ook`
+    pub fn blub() -> Self {
+        Self::from_config(&OOK.replace("Ook", "Blub")).unwrap()
+    }
+
+    /// Length of the token *words* at the start of *code*, if it is there
+    fn match_token(words: &[Vec<u8>], code: &[u8]) -> Option<usize> {
+        let mut len = 0;
+        for (i, word) in words.iter().enumerate() {
+            if i != 0 {
+                let space = code[len..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_whitespace())
+                    .count();
+                if space == 0 {
+                    return None;
+                }
+                len += space;
+            }
+            if !code[len..].starts_with(word) {
+                return None;
+            }
+            len += word.len();
+        }
+        Some(len)
+    }
+
+    /// Translates *code* to brainfuck, returning the commands and the byte
+    /// range of the token each came from in *code*
+    pub fn translate(&self, code: &[u8]) -> (Vec<u8>, Vec<Range<usize>>) {
+        let mut commands = Vec::new();
+        let mut spans = Vec::new();
+
+        let mut i = 0;
+        while i < code.len() {
+            let token = self.tokens.iter().find_map(|(words, command)| {
+                Self::match_token(words, &code[i..]).map(|len| (len, *command))
+            });
+            match token {
+                Some((len, command)) => {
+                    commands.push(command);
+                    spans.push(i..i + len);
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+        (commands, spans)
+    }
+
+    /// Parses code in this dialect to unoptimized AST, like `parse`. Errors
+    /// are for the tokens in *code*.
+    ///
+    /// # Examples
+    /// ```
+    /// let err = isbfc::Dialect::ook().parse(b"Ook. Ook.\nOok? Ook!").unwrap_err();
+    /// assert_eq!(err[0].span(), 10..19);
+    /// assert_eq!(err[0].to_string(), "2:1: error[E002]: ] found when not in a loop\nOok? Ook!\n^^^^^^^^^");
+    /// ```
+    pub fn parse(&self, code: &[u8]) -> Result<Vec<AST>, Vec<ParseError>> {
+        let (commands, spans) = self.translate(code);
+        crate::parse(&commands).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|err| {
+                    let span = spans[err.span().start].clone();
+                    err.relocate(code, span)
+                })
+                .collect()
+        })
+    }
+
+    /// Line and column of each loop's `[` token in *code*, like
+    /// `loop_positions`
+    pub fn loop_positions(&self, code: &[u8]) -> Vec<(usize, usize)> {
+        let (commands, spans) = self.translate(code);
+        commands
+            .iter()
+            .zip(spans)
+            .filter(|(c, _)| **c == b'[')
+            .map(|(_, span)| {
+                let (line, linenum, offset) = find_line(code, span.start);
+                (linenum + 1, column(line, offset))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_config() {
+        let err = |config| Dialect::from_config(config).unwrap_err();
+        assert_eq!(
+            err("; comment\n\nx a"),
+            "line 3: 'x' is not a brainfuck command"
+        );
+        assert_eq!(err("++ a"), "line 1: '++' is not a brainfuck command");
+        assert_eq!(err("+ a\n  -  "), "line 2: no token for '-'");
+        assert_eq!(
+            err("+ a  b\n- a b"),
+            "line 2: token 'a b' is already defined"
+        );
+    }
+
+    #[test]
+    fn words_are_separated() {
+        let dialect = Dialect::from_config("+ ab\n- a b").unwrap();
+        assert_eq!(dialect.translate(b"a b ab a\tb").0, b"-+-");
+    }
+
+    #[test]
+    fn prefix_token() {
+        let dialect = Dialect::from_config("+ a\n- a b\n> ab").unwrap();
+        let (commands, spans) = dialect.translate(b"a a\n  b ab a");
+        assert_eq!(commands, b"+->+");
+        assert_eq!(spans, [0..1, 2..7, 8..10, 11..12]);
+    }
+
+    #[test]
+    fn error_spans_across_lines() {
+        let code = b"Ook?\nOok! Ook!\n  Ook?";
+        let errors = Dialect::ook().parse(code).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].span(), 0..9);
+        assert_eq!(
+            errors[0].to_string(),
+            "1:1: error[E002]: ] found when not in a loop\nOok?\n^^^^"
+        );
+        assert_eq!(errors[1].span(), 10..21);
+        assert_eq!(
+            errors[1].to_string(),
+            "2:6: error[E001]: [ is never closed\nOok! Ook!\n     ^^^^"
+        );
+    }
+
+    #[test]
+    fn loop_positions() {
+        // The second [ is `Ook!` at the end of line 2, and `Ook?` on line 3
+        let code = "Ook! Ook? Ook.\nOok.   Ook!\nOok?\n\u{e9}Ook! Ook?".as_bytes();
+        let dialect = Dialect::ook();
+        assert_eq!(dialect.loop_positions(code), [(1, 1), (2, 8), (4, 2)]);
+    }
+}
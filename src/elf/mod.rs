@@ -15,9 +15,19 @@ use types::*;
 // * https://unix.stackexchange.com/questions/132036/why-does-readelf-show-system-v-as-my-os-instead-of-linux
 // * http://www.sco.com/developers/gabi/latest/ch4.eheader.html
 
-pub fn elf64_write(f: &mut impl Write, text: &[u8], bss_size: u64) -> io::Result<()> {
+/// Writes a static executable loading *text* at 0x401000, followed by
+/// *rodata* and then *bss_size* bytes of zeroed memory, each starting on a
+/// new page. The rodata segment is omitted if *rodata* is empty.
+pub fn elf64_write(
+    f: &mut impl Write,
+    text: &[u8],
+    rodata: &[u8],
+    bss_size: u64,
+) -> io::Result<()> {
     let size = text.len() as u64;
-    let hdr_size = (EHDR_SIZE + 2 * PHDR_SIZE) as u64;
+    let rodata_size = rodata.len() as u64;
+    let phnum = if rodata.is_empty() { 2 } else { 3 };
+    let hdr_size = (EHDR_SIZE + phnum * PHDR_SIZE) as u64;
     let hdr_size_padded = (hdr_size + 0x1000 - 1) & !(0x1000 - 1);
 
     let ehdr = Elf64_Ehdr {
@@ -41,7 +51,7 @@ pub fn elf64_write(f: &mut impl Write, text: &[u8], bss_size: u64) -> io::Result
         e_flags: 0,
         e_ehsize: EHDR_SIZE as u16,
         e_phentsize: PHDR_SIZE as u16,
-        e_phnum: 2,
+        e_phnum: phnum as u16,
         e_shentsize: SHDR_SIZE as u16,
         e_shnum: 0,
         e_shstrndx: 0,
@@ -58,7 +68,20 @@ pub fn elf64_write(f: &mut impl Write, text: &[u8], bss_size: u64) -> io::Result
         p_align: 0x1000,
     };
 
-    let bss_offset = (size + 0x1000 - 1) & !(0x1000 - 1);
+    let rodata_offset = (size + 0x1000 - 1) & !(0x1000 - 1);
+
+    let phdr_rodata = Elf64_Phdr {
+        p_type: PT_LOAD,
+        p_flags: PF_R,
+        p_offset: hdr_size_padded + rodata_offset,
+        p_vaddr: 0x40_1000 + rodata_offset,
+        p_paddr: 0x40_1000 + rodata_offset,
+        p_filesz: rodata_size,
+        p_memsz: rodata_size,
+        p_align: 0x1000,
+    };
+
+    let bss_offset = rodata_offset + ((rodata_size + 0x1000 - 1) & !(0x1000 - 1));
 
     let phdr_bss = Elf64_Phdr {
         p_type: PT_LOAD,
@@ -72,14 +95,23 @@ pub fn elf64_write(f: &mut impl Write, text: &[u8], bss_size: u64) -> io::Result
     };
 
     unsafe {
-        f.write_all(&transmute::<_, [u8; EHDR_SIZE]>(ehdr))?;
-        f.write_all(&transmute::<_, [u8; PHDR_SIZE]>(phdr_text))?;
-        f.write_all(&transmute::<_, [u8; PHDR_SIZE]>(phdr_bss))?;
+        f.write_all(&transmute::<Elf64_Ehdr, [u8; EHDR_SIZE]>(ehdr))?;
+        f.write_all(&transmute::<Elf64_Phdr, [u8; PHDR_SIZE]>(phdr_text))?;
+        if !rodata.is_empty() {
+            f.write_all(&transmute::<Elf64_Phdr, [u8; PHDR_SIZE]>(phdr_rodata))?;
+        }
+        f.write_all(&transmute::<Elf64_Phdr, [u8; PHDR_SIZE]>(phdr_bss))?;
     }
     for _ in 0..(hdr_size_padded - hdr_size) {
         f.write_all(b"0")?;
     }
     f.write_all(text)?;
+    if !rodata.is_empty() {
+        for _ in 0..(rodata_offset - size) {
+            f.write_all(b"\0")?;
+        }
+        f.write_all(rodata)?;
+    }
     Ok(())
 }
 
@@ -0,0 +1,242 @@
+//! Rewriting brainfuck source in a canonical layout, or without comments.
+//!
+//! Neither changes what the code does: `format` keeps every command and
+//! comment in order, and `minify` only removes commands that cancel out.
+
+use std::collections::VecDeque;
+use std::iter;
+use std::mem;
+
+/// Spaces each loop level is indented by
+const INDENT: usize = 4;
+
+/// Lines are at least this wide, however deeply they are indented
+const MIN_LINE_WIDTH: usize = 20;
+
+/// A part of the code, as `format` lays it out
+enum Part<'a> {
+    /// Commands other than brackets, without the whitespace between them
+    Code(Vec<u8>),
+    Open,
+    Close,
+    /// Lines of a comment, trimmed, with empty ones for blank lines
+    Comment(Vec<&'a [u8]>),
+}
+
+fn is_command(c: u8) -> bool {
+    b"+-<>[].,".contains(&c)
+}
+
+/// Lines of *text*, which is between commands or at the start or end of
+/// the code, as *start* and *end* say. Its first and last lines are shared
+/// with commands, so they are only kept if they have text, and so are
+/// blank lines at the start and end of the code.
+fn comment_lines(text: &[u8], start: bool, end: bool) -> Vec<&[u8]> {
+    let mut lines = (text.split(|c| *c == b'\n'))
+        .map(<[u8]>::trim_ascii)
+        .collect::<VecDeque<_>>();
+    if lines.front().is_some_and(|line| line.is_empty()) {
+        lines.pop_front();
+    }
+    if lines.back().is_some_and(|line| line.is_empty()) {
+        lines.pop_back();
+    }
+    while start && lines.front().is_some_and(|line| line.is_empty()) {
+        lines.pop_front();
+    }
+    while end && lines.back().is_some_and(|line| line.is_empty()) {
+        lines.pop_back();
+    }
+    lines.into()
+}
+
+/// Splits *code* into parts, dropping whitespace
+fn parts(code: &[u8]) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut commands = Vec::new();
+    // Start of the text after the last command
+    let mut text_start = 0;
+
+    for (i, &c) in code.iter().enumerate() {
+        if !is_command(c) {
+            continue;
+        }
+        let start = parts.is_empty() && commands.is_empty();
+        let lines = comment_lines(&code[text_start..i], start, false);
+        text_start = i + 1;
+
+        if (!lines.is_empty() || b"[]".contains(&c)) && !commands.is_empty() {
+            parts.push(Part::Code(mem::take(&mut commands)));
+        }
+        if !lines.is_empty() {
+            parts.push(Part::Comment(lines));
+        }
+        match c {
+            b'[' => parts.push(Part::Open),
+            b']' => parts.push(Part::Close),
+            _ => commands.push(c),
+        }
+    }
+
+    let start = parts.is_empty() && commands.is_empty();
+    let lines = comment_lines(&code[text_start..], start, true);
+    if !commands.is_empty() {
+        parts.push(Part::Code(commands));
+    }
+    if !lines.is_empty() {
+        parts.push(Part::Comment(lines));
+    }
+    parts
+}
+
+/// Rewrites *code* with each loop's brackets on their own lines and its
+/// body indented a level, filling lines up to *line_width* columns with
+/// commands. Comments keep their lines, and the blank lines between them,
+/// and are only indented.
+///
+/// # Examples
+/// ```
+/// let code = isbfc::format(b"add two +[->++<] done", 80);
+/// assert_eq!(code, b"add two\n+\n[\n    ->++<\n]\ndone\n");
+/// ```
+pub fn format(code: &[u8], line_width: usize) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut depth = 0usize;
+    for part in parts(code) {
+        if let Part::Close = part {
+            depth = depth.saturating_sub(1);
+        }
+        let indent = INDENT * depth;
+        let available = line_width.saturating_sub(indent).max(MIN_LINE_WIDTH);
+        let mut push_line = |line: &[u8]| {
+            if !line.is_empty() {
+                output.extend(iter::repeat_n(b' ', indent));
+                output.extend_from_slice(line);
+            }
+            output.push(b'\n');
+        };
+
+        match part {
+            Part::Code(commands) => {
+                for line in commands.chunks(available) {
+                    push_line(line);
+                }
+            }
+            Part::Open => {
+                push_line(b"[");
+                depth += 1;
+            }
+            Part::Close => push_line(b"]"),
+            Part::Comment(lines) => {
+                for line in lines {
+                    push_line(line);
+                }
+            }
+        }
+    }
+    output
+}
+
+/// Strips everything but commands from *code*, and commands that are
+/// undone by the command right after them
+///
+/// # Examples
+/// ```
+/// assert_eq!(isbfc::minify(b"++- move >[<>-] right"), b"+>[-]");
+/// ```
+pub fn minify(code: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for &c in code.iter().filter(|c| is_command(**c)) {
+        let cancels = match output.last() {
+            Some(b'+') => c == b'-',
+            Some(b'-') => c == b'+',
+            Some(b'>') => c == b'<',
+            Some(b'<') => c == b'>',
+            _ => false,
+        };
+        if cancels {
+            output.pop();
+        } else {
+            output.push(c);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_layout() {
+        let code = b"\n\nAdds two numbers\n  by looping\n\n\nset up\n++>+++<\n[ ->+<  move one\n\n  over\n]  done\n\n";
+        let expected = "\
+Adds two numbers
+by looping
+
+
+set up
+++>+++<
+[
+    ->+<
+    move one
+
+    over
+]
+done
+";
+        assert_eq!(String::from_utf8(format(code, 80)).unwrap(), expected);
+    }
+
+    #[test]
+    fn blank_lines_between_commands() {
+        assert_eq!(format(b"+ +\n-\n\n>  >\n\n\n<", 80), b"++-\n\n>>\n\n\n<\n");
+    }
+
+    #[test]
+    fn wraps_commands() {
+        let code = "+".repeat(30) + "[" + &">".repeat(30) + "]";
+        let expected = "+".repeat(25)
+            + "\n"
+            + &"+".repeat(5)
+            + "\n[\n    "
+            + &">".repeat(21)
+            + "\n    "
+            + &">".repeat(9)
+            + "\n]\n";
+        assert_eq!(
+            String::from_utf8(format(code.as_bytes(), 25)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn min_line_width() {
+        let code = "[".repeat(3) + &"-".repeat(25) + &"]".repeat(3);
+        let lines = String::from_utf8(format(code.as_bytes(), 20)).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines[3], " ".repeat(12) + &"-".repeat(MIN_LINE_WIDTH));
+        assert_eq!(lines[4], " ".repeat(12) + "-----");
+    }
+
+    #[test]
+    fn format_is_stable() {
+        let code = b"loop +[->+<] then. text\n\n  more\n";
+        let once = format(code, 30);
+        assert_eq!(format(&once, 30), once);
+    }
+
+    #[test]
+    fn minify_cancels() {
+        assert_eq!(minify(b"+><-"), b"");
+        assert_eq!(minify(b"+-+"), b"+");
+        assert_eq!(minify(b"<[>]<"), b"<[>]<");
+        assert_eq!(minify(b"+[-]-"), b"+[-]-");
+    }
+
+    #[test]
+    fn minify_keeps_io() {
+        assert_eq!(minify(b"read, then write. twice..\n"), b",...");
+        assert_eq!(minify(b"+.-"), b"+.-");
+    }
+}
@@ -2,24 +2,38 @@
 //!
 //! # Examples
 //! ```
-//! fn main() {
-//!     // 2048 is the tape length to use
-//!     let assembly = isbfc::parse(",[.,]").unwrap().optimize().compile(2048);
-//!     print!("{}", assembly);
-//! }
+//! use isbfc::codegen::c_codegen::{codegen, CellType};
+//! use isbfc::{NewOptimizer, Optimizer};
+//!
+//! let ast = isbfc::parse(b",[.,]").unwrap();
+//! let lir = NewOptimizer.optimize(&ast, &NewOptimizer.passes_at(2), CellType::U64);
+//! // 2048 is the tape length to use
+//! let c = codegen(&lir, CellType::U64, 2048);
+//! print!("{}", c);
 //! ```
 
 mod assembly;
 pub mod codegen;
+mod dialect;
 mod elf;
+mod format;
+mod lint;
 pub mod lir;
 mod optimizer;
 mod parser;
 
 pub use crate::assembly::{assemble, link};
+pub use crate::codegen::brainfuck::optimize_to_brainfuck;
+pub use crate::dialect::Dialect;
 pub use crate::elf::{elf64_get_section, elf64_write};
+pub use crate::format::{format, minify};
+pub use crate::lint::{lint, Lint, LintKind};
 pub use crate::lir::{LIRBuilder, LIR};
 pub use crate::optimizer::{
-    NewOptimizer, OldOptimizer, Optimizer, SimpleAddOptimizer, SimpleOptimizer, OPTIMIZERS,
+    partial_eval, LoopRemark, NewOptimizer, OldOptimizer, Optimizer, PartialEval, Pass, PassConfig,
+    SimpleAddOptimizer, SimpleOptimizer, DEFAULT_STEP_BUDGET, MAX_LEVEL, OPTIMIZERS,
+};
+pub use crate::parser::{
+    ast_to_flat, flat_to_ast, loop_positions, parse, parse_extended, parse_flat,
+    parse_flat_extended, FlatAST, ParseError, ParseErrorType, StreamParser, AST,
 };
-pub use crate::parser::{parse, AST};
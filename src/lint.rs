@@ -0,0 +1,323 @@
+//! Warnings about brainfuck that is valid, but probably not what was meant.
+//!
+//! These need where each command is and the comments around it, which the
+//! `AST` doesn't keep, so they work on the code itself.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use crate::parser::{column, find_line, parse_flat, write_marked_line};
+use crate::ParseError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LintKind {
+    /// `Cancels(a, b)` Command *a* is undone by the command *b* after it
+    Cancels(u8, u8),
+    /// A loop right after another loop, when the cell is zero
+    NeverRuns,
+    /// `[]`, which never ends if the cell isn't zero
+    EmptyLoop,
+    /// Code after a loop that is entered and never ends
+    Unreachable,
+    /// `Comment(c)` A `.` or `,` that looks like part of a comment
+    Comment(u8),
+}
+use LintKind::*;
+
+impl LintKind {
+    /// A code identifying the kind of warning, which doesn't change between
+    /// versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            Cancels(..) => "W001",
+            NeverRuns => "W002",
+            EmptyLoop => "W003",
+            Unreachable => "W004",
+            Comment(_) => "W005",
+        }
+    }
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cancels(a, b) => write!(f, "`{}{}` cancel out", *a as char, *b as char),
+            NeverRuns => write!(
+                f,
+                "loop never runs, since the loop before it leaves the cell zero"
+            ),
+            EmptyLoop => write!(f, "`[]` never ends if the cell isn't zero"),
+            Unreachable => write!(f, "unreachable, since the loop before it never ends"),
+            Comment(c) => write!(f, "`{}` in comment text is a command", *c as char),
+        }
+    }
+}
+
+/// A warning about a command
+#[derive(Debug)]
+pub struct Lint {
+    kind: LintKind,
+    line: Vec<u8>,
+    /// Line number, starting at 1
+    linenum: usize,
+    /// Offset in bytes of the command in *line*
+    offset: usize,
+    /// Offset in bytes of the command in the code
+    pos: usize,
+}
+
+impl Lint {
+    fn new(kind: LintKind, code: &[u8], pos: usize) -> Self {
+        let (line, linenum, offset) = find_line(code, pos);
+        Self {
+            kind,
+            line: line.into(),
+            linenum: linenum + 1,
+            offset,
+            pos,
+        }
+    }
+
+    pub fn kind(&self) -> LintKind {
+        self.kind
+    }
+
+    /// Line of the command, starting at 1
+    pub fn line(&self) -> usize {
+        self.linenum
+    }
+
+    /// Column of the command in characters, starting at 1
+    pub fn column(&self) -> usize {
+        column(&self.line, self.offset)
+    }
+
+    /// Byte range of the command in the code
+    pub fn span(&self) -> Range<usize> {
+        self.pos..self.pos + 1
+    }
+}
+
+impl fmt::Display for Lint {
+    /// Shows the warning like a `ParseError`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}:{}: warning[{}]: {}",
+            self.linenum,
+            self.column(),
+            self.kind.code(),
+            self.kind
+        )?;
+        write_marked_line(f, &self.line, self.offset, 1)
+    }
+}
+
+/// Whether the loop body *body* never changes the loop's cell or reads
+/// input, so once entered it never ends
+fn never_ends(body: &[(usize, u8)]) -> bool {
+    let mut shift = 0;
+    let mut add = 0;
+    for (_, c) in body {
+        match c {
+            b'>' => shift += 1,
+            b'<' => shift -= 1,
+            b'+' if shift == 0 => add += 1,
+            b'-' if shift == 0 => add -= 1,
+            b'+' | b'-' | b'.' => {}
+            _ => return false,
+        }
+    }
+    shift == 0 && add == 0
+}
+
+/// Finds the first loop that is entered and never ends, given the tape
+/// starts zeroed, and returns the index in *commands* of its `]`. Only
+/// values set since the last loop or input are tracked.
+fn find_infinite_loop(commands: &[(usize, u8)], matching: &[usize]) -> Option<usize> {
+    // Values of cells relative to the cursor, if known. Others are zero
+    // while *zeroed* is set, and unknown after that.
+    let mut cells = HashMap::<i32, Option<i64>>::new();
+    let mut zeroed = true;
+    let mut cursor = 0;
+
+    let mut i = 0;
+    while i < commands.len() {
+        let value = match cells.get(&cursor) {
+            Some(value) => *value,
+            None if zeroed => Some(0),
+            None => None,
+        };
+        match commands[i].1 {
+            b'>' => cursor += 1,
+            b'<' => cursor -= 1,
+            b'+' => {
+                cells.insert(cursor, value.map(|x| x + 1));
+            }
+            b'-' => {
+                cells.insert(cursor, value.map(|x| x - 1));
+            }
+            b',' => {
+                cells.insert(cursor, None);
+            }
+            b'[' if value == Some(0) => {
+                // Never entered, so nothing is changed
+                i = matching[i];
+            }
+            b'[' => {
+                let end = matching[i];
+                // Nonzero for any cell size
+                let entered = value.is_some_and(|x| x.rem_euclid(256) != 0);
+                if entered && never_ends(&commands[i + 1..end]) {
+                    return Some(end);
+                }
+                cells.clear();
+                zeroed = false;
+            }
+            b']' => {
+                cells.clear();
+                cells.insert(0, Some(0));
+                zeroed = false;
+                cursor = 0;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Finds suspicious code in *code*. If brackets don't match, returns an
+/// error for each unmatched one instead, like `parse`.
+///
+/// # Examples
+/// ```
+/// let lints = isbfc::lint(b"+[-]-+").unwrap();
+/// assert_eq!(lints[0].to_string(), "1:5: warning[W001]: `-+` cancel out\n+[-]-+\n    ^");
+/// assert!(isbfc::lint(b"+[-]]").is_err());
+/// ```
+pub fn lint(code: &[u8]) -> Result<Vec<Lint>, Vec<ParseError>> {
+    parse_flat(code)?;
+
+    let commands = code
+        .iter()
+        .cloned()
+        .enumerate()
+        .filter(|(_, c)| b"+-<>[].,".contains(c))
+        .collect::<Vec<_>>();
+
+    let mut matching = vec![0; commands.len()];
+    let mut loops = Vec::new();
+    for (i, (_, c)) in commands.iter().enumerate() {
+        match c {
+            b'[' => loops.push(i),
+            b']' => {
+                // Brackets match, since `parse_flat` accepted them
+                let start = loops.pop().unwrap();
+                matching[start] = i;
+                matching[i] = start;
+            }
+            _ => {}
+        }
+    }
+
+    let mut lints = Vec::new();
+    for pair in commands.windows(2) {
+        let ((pos, a), (_, b)) = (pair[0], pair[1]);
+        match (a, b) {
+            (b'+', b'-') | (b'-', b'+') | (b'>', b'<') | (b'<', b'>') => {
+                lints.push(Lint::new(Cancels(a, b), code, pos));
+            }
+            (b']', b'[') => lints.push(Lint::new(NeverRuns, code, pair[1].0)),
+            (b'[', b']') => lints.push(Lint::new(EmptyLoop, code, pos)),
+            _ => {}
+        }
+    }
+
+    if let Some(end) = find_infinite_loop(&commands, &matching) {
+        if let Some((pos, _)) = commands.get(end + 1) {
+            lints.push(Lint::new(Unreachable, code, *pos));
+        }
+    }
+
+    for (pos, c) in commands {
+        let after_text = pos > 0 && code[pos - 1].is_ascii_alphanumeric();
+        if b".,".contains(&c) && after_text {
+            lints.push(Lint::new(Comment(c), code, pos));
+        }
+    }
+
+    lints.sort_by_key(|lint| lint.pos);
+    Ok(lints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Kind and position of each lint in *code*
+    fn lints(code: &[u8]) -> Vec<(LintKind, usize)> {
+        (lint(code).unwrap().iter())
+            .map(|lint| (lint.kind(), lint.span().start))
+            .collect()
+    }
+
+    #[test]
+    fn cancels() {
+        assert_eq!(
+            lints(b"+- ><\n<>-+"),
+            [
+                (Cancels(b'+', b'-'), 0),
+                (Cancels(b'>', b'<'), 3),
+                (Cancels(b'<', b'>'), 6),
+                (Cancels(b'-', b'+'), 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn never_runs() {
+        assert_eq!(lints(b",[-]\n[.]"), [(NeverRuns, 5)]);
+    }
+
+    #[test]
+    fn empty_loop() {
+        assert_eq!(lints(b",[]"), [(EmptyLoop, 1)]);
+    }
+
+    #[test]
+    fn unreachable() {
+        assert_eq!(lints(b"+[]>."), [(EmptyLoop, 1), (Unreachable, 3)]);
+        // The body never changes the loop's cell
+        assert_eq!(lints(b"+[>+<]."), [(Unreachable, 6)]);
+    }
+
+    #[test]
+    fn not_unreachable() {
+        // Loops that end, or aren't known to be entered
+        assert!(lints(b"+[-]>+.").is_empty());
+        assert!(lints(b"+[->+<]>.").is_empty());
+        assert_eq!(lints(b",[]."), [(EmptyLoop, 1)]);
+        // Zero, so never entered
+        assert!(lints(b"+-+-").iter().all(|(kind, _)| *kind != Unreachable));
+        assert!(lints(b"[>+<].").is_empty());
+    }
+
+    #[test]
+    fn comment() {
+        assert_eq!(
+            lints(b"Hello, world.\n. ,"),
+            [(Comment(b','), 5), (Comment(b'.'), 12)]
+        );
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let errors = lint(b"]+[").unwrap_err();
+        let codes = (errors.iter())
+            .map(|err| err.kind().code())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, ["E002", "E001"]);
+    }
+}
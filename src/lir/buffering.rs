@@ -0,0 +1,105 @@
+//! Explicit flushing of buffered output
+
+use std::fmt;
+use std::mem;
+use std::slice;
+use std::str::FromStr;
+
+use super::{LIRBuilder, LIR};
+
+/// Size of the runtime output buffer used with `OutputBuffering::Line` and
+/// `OutputBuffering::Full`
+pub const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// When the runtime writes out data appended by `LIR::Output`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum OutputBuffering {
+    /// Every `Output` is written immediately
+    None,
+    /// Output is written when a newline is appended or the buffer fills
+    Line,
+    /// Output is written only when the buffer fills
+    Full,
+}
+
+impl FromStr for OutputBuffering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(OutputBuffering::None),
+            "line" => Ok(OutputBuffering::Line),
+            "full" => Ok(OutputBuffering::Full),
+            _ => Err(format!("unknown output buffering '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputBuffering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputBuffering::None => write!(f, "none"),
+            OutputBuffering::Line => write!(f, "line"),
+            OutputBuffering::Full => write!(f, "full"),
+        }
+    }
+}
+
+fn buffer_output_iter(lir: &mut LIRBuilder, input: &[LIR]) {
+    // Blocks being buffered, innermost last: each block, the rest of the
+    // LIR around it, and that LIR's output so far
+    let mut blocks: Vec<(&LIR, slice::Iter<LIR>, LIRBuilder)> = Vec::new();
+
+    let mut rest = input.iter();
+    loop {
+        let i = match rest.next() {
+            Some(i) => i,
+            None => match blocks.pop() {
+                Some((block, outer_rest, outer)) => {
+                    rest = outer_rest;
+                    let new_body = mem::replace(lir, outer).build();
+                    match block {
+                        LIR::Loop { cond, .. } => lir.loop_(cond.clone(), new_body),
+                        LIR::If { cond, .. } => lir.if_(cond.clone(), new_body),
+                        _ => unreachable!("only loops and ifs have bodies"),
+                    };
+                    continue;
+                }
+                None => return,
+            },
+        };
+        match i {
+            LIR::Loop { body, .. } | LIR::If { body, .. } => {
+                blocks.push((i, mem::replace(&mut rest, body.iter()), mem::take(lir)));
+            }
+            LIR::Input(..) => {
+                // Make sure any prompt is visible before blocking on input
+                lir.flush();
+                lir.push(i.clone());
+            }
+            LIR::DebugDump(_) => {
+                // So the dump shows where the program's output is up to
+                lir.flush();
+                lir.push(i.clone());
+            }
+            _ => {
+                lir.push(i.clone());
+            }
+        }
+    }
+}
+
+/// Declares the output buffering policy for a program, and inserts a
+/// `Flush` before every `Input` and `DebugDump` and at exit, so buffered
+/// output is never delayed past a read or lost.
+pub fn buffer_output(lir: &[LIR], buffering: OutputBuffering) -> Vec<LIR> {
+    let mut builder = LIRBuilder::new();
+    builder.declare_output_buffering(buffering);
+    if buffering == OutputBuffering::None {
+        builder.lir.extend_from_slice(lir);
+    } else {
+        buffer_output_iter(&mut builder, lir);
+        builder.flush();
+    }
+    builder.build()
+}
@@ -0,0 +1,69 @@
+//! Lowering of structured control flow to labels and jumps
+
+use std::mem;
+
+use super::{LIRBuilder, LIR};
+
+#[derive(Default)]
+struct FlattenState {
+    lir: LIRBuilder,
+    loopnum: i32,
+    ifnum: i32,
+}
+
+fn flatten_iter(state: &mut FlattenState, lir: &[LIR]) {
+    // Blocks being flattened, innermost last: the labels and condition to
+    // end each with, and the rest of the LIR around it
+    let mut blocks = Vec::new();
+
+    let mut rest = lir.iter();
+    loop {
+        let i = match rest.next() {
+            Some(i) => i,
+            None => match blocks.pop() {
+                Some((endlabel, start, outer_rest)) => {
+                    rest = outer_rest;
+                    state.lir.label(endlabel);
+                    if let Some((cond, startlabel)) = start {
+                        state.lir.jnz(cond, startlabel);
+                    }
+                    continue;
+                }
+                None => return,
+            },
+        };
+        match i {
+            LIR::Loop { cond, body } => {
+                state.loopnum += 1;
+                let startlabel = format!("loop{}", state.loopnum);
+                let endlabel = format!("endloop{}", state.loopnum);
+                state.lir.jp(endlabel.clone());
+                state.lir.label(startlabel.clone());
+
+                let outer_rest = mem::replace(&mut rest, body.iter());
+                blocks.push((endlabel, Some((cond.clone(), startlabel)), outer_rest));
+            }
+            LIR::If { cond, body } => {
+                state.ifnum += 1;
+                let endlabel = format!("endif{}", state.ifnum);
+                state.lir.jz(cond.clone(), endlabel.clone());
+
+                blocks.push((endlabel, None, mem::replace(&mut rest, body.iter())));
+            }
+            _ => {
+                state.lir.push(i.clone());
+            }
+        }
+    }
+}
+
+/// Replaces every `Loop` and `If` with equivalent `Label`, `Jp`, `Jz`, and
+/// `Jnz` instructions, for backends that only handle unstructured control flow.
+///
+/// Generated labels are named `loopN`, `endloopN`, and `endifN`, so
+/// any labels already present should not use those names.
+pub fn flatten(lir: &[LIR]) -> Vec<LIR> {
+    let mut state = FlattenState::default();
+    flatten_iter(&mut state, lir);
+    state.lir.build()
+}
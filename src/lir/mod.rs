@@ -0,0 +1,302 @@
+#![allow(dead_code)]
+
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+mod buffering;
+mod flatten;
+pub use buffering::{buffer_output, OutputBuffering, OUTPUT_BUFFER_SIZE};
+pub use flatten::flatten;
+
+pub type CowStr = Cow<'static, str>;
+
+// Need to consider fact that output buffer has 8-bit characters, while tape may not
+
+pub mod prelude {
+    pub use super::{LIRBuilder, LVal, RVal, LIR};
+    pub use LVal::*;
+    pub use RVal::Immediate;
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub enum LVal {
+    Reg(u32),
+    Tape(i32),
+    Buf(CowStr, usize),
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub enum RVal {
+    Reg(u32),
+    Tape(i32),
+    Buf(CowStr, usize),
+    Immediate(i64),
+}
+
+/// The width of a tape cell. Cells wrap around on overflow.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CellType {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellType {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellType::U8 => 8,
+            CellType::U16 => 16,
+            CellType::U32 => 32,
+            CellType::U64 => 64,
+        }
+    }
+
+    /// Reduces *value* modulo the cell size, giving the equivalent value
+    /// with the cell's bits sign extended to 64
+    pub fn wrap(self, value: i64) -> i64 {
+        let unused = 64 - self.bits();
+        (value << unused) >> unused
+    }
+}
+
+impl FromStr for CellType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "8" => Ok(CellType::U8),
+            "16" => Ok(CellType::U16),
+            "32" => Ok(CellType::U32),
+            "64" => Ok(CellType::U64),
+            _ => Err(format!("unsupported cell size '{}'", s)),
+        }
+    }
+}
+
+impl PartialEq<RVal> for LVal {
+    fn eq(&self, other: &RVal) -> bool {
+        // TODO avoid clone
+        &RVal::from(self.clone()) == other
+    }
+}
+
+impl PartialEq<LVal> for RVal {
+    fn eq(&self, other: &LVal) -> bool {
+        // TODO avoid clone
+        self == &RVal::from(other.clone())
+    }
+}
+
+impl From<LVal> for RVal {
+    fn from(lval: LVal) -> Self {
+        match lval {
+            LVal::Reg(num) => RVal::Reg(num),
+            LVal::Tape(offset) => RVal::Tape(offset),
+            LVal::Buf(name, offset) => RVal::Buf(name, offset),
+        }
+    }
+}
+
+impl fmt::Debug for LVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", RVal::from(self.clone()))
+    }
+}
+
+impl fmt::Debug for RVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RVal::Reg(num) => write!(f, "%r{}", num),
+            RVal::Tape(offset) => write!(f, "TAPE[{}]", offset),
+            RVal::Buf(name, offset) => write!(f, "{}[{}]", name, offset),
+            RVal::Immediate(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// LIR is isbfc's low level intermediate representation. In principle,
+/// it serves a similar roles to LLVM IR, but it is much similer
+/// and includes some Brainfuck specfic features.
+///
+/// Goals:
+/// * Architecture agnostic
+/// * Attempt to represent anything a Brainfuck compiler might want to
+///   generate, without bias for a specific optimization design.
+/// * Cell size agnostic
+///
+/// Control flow can be expressed either with raw labels and jumps, or
+/// with the structured `Loop` and `If` forms. Optimizers generally emit
+/// the structured forms; backends that prefer labels can lower them with
+/// [`flatten`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum LIR {
+    Shift(i32),
+    Mul(LVal, RVal, RVal),
+    Add(LVal, RVal, RVal),
+    Sub(LVal, RVal, RVal),
+    Mov(LVal, RVal),
+    /// Sets *dest* to the first value if *cond* is not zero, and the
+    /// second otherwise, without branching
+    Select(LVal, RVal, RVal, RVal),
+    Label(CowStr),
+    Jp(CowStr),
+    Jz(RVal, CowStr),
+    Jnz(RVal, CowStr),
+    /// Moves the cursor by *stride* cells at a time until the current cell
+    /// is zero, like a `Loop` containing only a `Shift`
+    Scan(i32),
+    /// Runs *body* repeatedly while *cond* is not zero
+    Loop {
+        cond: RVal,
+        body: Vec<LIR>,
+    },
+    /// Runs *body* once if *cond* is not zero
+    If {
+        cond: RVal,
+        body: Vec<LIR>,
+    },
+    DeclareBssBuf(CowStr, usize),
+    /// Declares a read-only buffer holding the given bytes, which can be
+    /// written with `Output` like any other buffer
+    DeclareRodataBuf(CowStr, Vec<u8>),
+    /// Sets how the runtime buffers output; see [`buffer_output`]
+    DeclareOutputBuffering(OutputBuffering),
+    Input(CowStr, usize, usize),
+    /// Appends part of a buffer to the program's output, which is written
+    /// out according to the declared `OutputBuffering`
+    Output(CowStr, usize, usize),
+    /// Writes out any output that has been buffered so far
+    Flush,
+    /// Writes the cells around the cell at *offset* to stderr, for the `#`
+    /// extension
+    DebugDump(i32),
+    /// Declares input the program reads instead of stdin, embedded in the
+    /// code with the `!` extension
+    DeclareInput(Vec<u8>),
+}
+
+impl Drop for LIR {
+    /// Drops nested blocks from a stack, since LIR for deeply nested
+    /// programs would overflow it if they were dropped recursively
+    fn drop(&mut self) {
+        if let LIR::Loop { body, .. } | LIR::If { body, .. } = self {
+            let mut stack = mem::take(body);
+            while let Some(mut lir) = stack.pop() {
+                if let LIR::Loop { body, .. } | LIR::If { body, .. } = &mut lir {
+                    stack.append(body);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LIRBuilder {
+    lir: Vec<LIR>,
+}
+
+/// Defines a method that pushes a token to self.lir
+macro_rules! pusher {
+    ( $name:ident, $variant:ident, $( $arg:ident : $type:ty ),* ) => {
+        pub fn $name(&mut self, $( $arg: $type ),*) -> &mut Self {
+            self.lir.push(LIR::$variant($( $arg.into() ),*));
+            self
+        }
+    }
+}
+
+impl LIRBuilder {
+    pub fn new() -> Self {
+        Self { lir: Vec::new() }
+    }
+
+    pub fn push(&mut self, lir: LIR) -> &mut Self {
+        self.lir.push(lir);
+        self
+    }
+
+    pusher!(shift, Shift, offset: i32);
+    pusher!(scan, Scan, stride: i32);
+    pusher!(label, Label, name: impl Into<CowStr>);
+    pusher!(
+        declare_bss_buf,
+        DeclareBssBuf,
+        name: impl Into<CowStr>,
+        size: usize
+    );
+    pusher!(
+        declare_rodata_buf,
+        DeclareRodataBuf,
+        name: impl Into<CowStr>,
+        data: Vec<u8>
+    );
+    pusher!(
+        declare_output_buffering,
+        DeclareOutputBuffering,
+        buffering: OutputBuffering
+    );
+    pusher!(
+        input,
+        Input,
+        name: impl Into<CowStr>,
+        offset: usize,
+        size: usize
+    );
+    pusher!(debug_dump, DebugDump, offset: i32);
+    pusher!(declare_input, DeclareInput, data: Vec<u8>);
+    pusher!(
+        output,
+        Output,
+        name: impl Into<CowStr>,
+        offset: usize,
+        size: usize
+    );
+    pusher!(mul, Mul, dest: LVal, a: impl Into<RVal>, b: impl Into<RVal>);
+    pusher!(add, Add, dest: LVal, a: impl Into<RVal>, b: impl Into<RVal>);
+    pusher!(sub, Sub, dest: LVal, a: impl Into<RVal>, b: impl Into<RVal>);
+    pusher!(mov, Mov, dest: LVal, src: impl Into<RVal>);
+    pusher!(
+        select,
+        Select,
+        dest: LVal,
+        cond: impl Into<RVal>,
+        a: impl Into<RVal>,
+        b: impl Into<RVal>
+    );
+    pusher!(jp, Jp, name: impl Into<CowStr>);
+    pusher!(jz, Jz, comparand: impl Into<RVal>, name: impl Into<CowStr>);
+    pusher!(
+        jnz,
+        Jnz,
+        comparand: impl Into<RVal>,
+        name: impl Into<CowStr>
+    );
+
+    pub fn flush(&mut self) -> &mut Self {
+        self.lir.push(LIR::Flush);
+        self
+    }
+
+    pub fn loop_(&mut self, cond: impl Into<RVal>, body: Vec<LIR>) -> &mut Self {
+        self.lir.push(LIR::Loop {
+            cond: cond.into(),
+            body,
+        });
+        self
+    }
+
+    pub fn if_(&mut self, cond: impl Into<RVal>, body: Vec<LIR>) -> &mut Self {
+        self.lir.push(LIR::If {
+            cond: cond.into(),
+            body,
+        });
+        self
+    }
+
+    pub fn build(self) -> Vec<LIR> {
+        self.lir
+    }
+}
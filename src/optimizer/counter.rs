@@ -0,0 +1,62 @@
+//! Closed forms for counter loops.
+//!
+//! A loop whose cell changes by a constant *step* each iteration, and which
+//! otherwise only adds constants to cells, runs a number of times that can
+//! be found with arithmetic modulo the cell size. Each other cell then
+//! changes by that count times what the body adds to it.
+
+use crate::lir::CellType;
+
+/// Multiplicative inverse of odd *value* modulo 2^64, which is also its
+/// inverse modulo any smaller power of two
+fn inverse(value: u64) -> u64 {
+    debug_assert!(value & 1 == 1);
+    // Newton's method; each step doubles the number of correct bits, and
+    // `value` is its own inverse modulo 8
+    let mut inv = value;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u64.wrapping_sub(value.wrapping_mul(inv)));
+    }
+    inv
+}
+
+fn mask(bits: u32) -> u64 {
+    u64::MAX >> (64 - bits)
+}
+
+/// Number of times a loop runs when its cell starts at *start* and
+/// changes by *step* each iteration, or `None` if it never reaches zero.
+///
+/// The count is modulo the cell size; like any other value, it is
+/// given sign extended.
+pub fn trip_count(start: i64, step: i64, cell: CellType) -> Option<i64> {
+    let mask = mask(cell.bits());
+    let start = start as u64 & mask;
+    let step = step as u64 & mask;
+    if start == 0 {
+        return Some(0);
+    } else if step == 0 {
+        return None;
+    }
+
+    // With step = 2^k * odd, start must be a multiple of 2^k, and the count
+    // is only determined modulo 2^(bits - k)
+    let k = step.trailing_zeros();
+    if start & ((1 << k) - 1) != 0 {
+        return None;
+    }
+    let count = ((start.wrapping_neg() & mask) >> k).wrapping_mul(inverse(step >> k));
+    Some(cell.wrap((count & (mask >> k)) as i64))
+}
+
+/// For a loop whose cell changes by odd *step* each iteration, the value
+/// the starting value of the cell must be multiplied by to give the number
+/// of times it runs. Returns `None` for even steps, where the count is not
+/// a multiple of the starting value and the loop need not terminate.
+pub fn trip_multiplier(step: i64, cell: CellType) -> Option<i64> {
+    if step & 1 == 0 {
+        None
+    } else {
+        Some(cell.wrap(inverse(step as u64).wrapping_neg() as i64))
+    }
+}
@@ -1,21 +1,46 @@
+use crate::lir::CellType;
 use crate::{AST, LIR};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::io::Write;
 
+mod counter;
 mod new;
-mod old;
+pub(crate) mod old;
+mod output;
+mod partial_eval;
+mod passes;
+mod remarks;
 mod simple;
 mod simple_add;
 
 pub use new::NewOptimizer;
 pub use old::OldOptimizer;
+pub use partial_eval::{partial_eval, PartialEval, DEFAULT_STEP_BUDGET};
+pub use passes::{Pass, PassConfig, MAX_LEVEL};
+pub use remarks::LoopRemark;
 pub use simple::SimpleOptimizer;
 pub use simple_add::SimpleAddOptimizer;
 
 pub trait Optimizer: Sync {
-    fn optimize(&self, ast: &[AST], level: u32) -> Vec<LIR>;
-    fn dumpir(&self, ast: &[AST], level: u32, file: &mut dyn Write) -> std::io::Result<()>;
+    /// The passes this optimizer can run, in order
+    fn passes(&self) -> &'static [Pass] {
+        &[]
+    }
+
+    /// The passes enabled at optimization *level*
+    fn passes_at(&self, level: u32) -> PassConfig {
+        PassConfig::new(self.passes(), level)
+    }
+
+    fn optimize(&self, ast: &[AST], passes: &PassConfig, cell: CellType) -> Vec<LIR>;
+    fn dumpir(
+        &self,
+        ast: &[AST],
+        passes: &PassConfig,
+        cell: CellType,
+        file: &mut dyn Write,
+    ) -> std::io::Result<()>;
 }
 
 lazy_static! {
@@ -1,16 +1,24 @@
 use std::collections::HashMap;
+use std::mem;
+use std::slice;
 
 use super::dag::Value;
 use super::ir::IR;
+use crate::lir::LVal;
+use crate::optimizer::output::OutputBuffer;
 use crate::{LIRBuilder, LIR};
 
 #[derive(Default)]
 struct CompileState {
     lir: LIRBuilder,
-    loopnum: i32,
-    ifnum: i32,
-    outbuffsize: usize,
+    outbuf: OutputBuffer,
     regnum: u32,
+    /// Offset from the real cursor of the cell the IR's offsets are
+    /// relative to. Shifts only add to this, rather than moving the cursor,
+    /// until a loop needs it moved.
+    offset: i32,
+    /// Whether loops are compiled without moving the cursor to their cell
+    offsets: bool,
 }
 
 impl CompileState {
@@ -20,51 +28,94 @@ impl CompileState {
         self.regnum += 1;
         r
     }
+
+    /// Tape offset from the real cursor for an offset in the IR
+    fn tape(&self, offset: i32) -> LVal {
+        LVal::Tape(self.offset + offset)
+    }
+
+    /// Moves the real cursor to the cell the IR's offsets are relative to
+    fn sync_cursor(&mut self) {
+        if self.offset != 0 {
+            self.lir.shift(self.offset);
+            self.offset = 0;
+        }
+    }
 }
 
 fn ir_to_lir_iter(state: &mut CompileState, ir: &[IR]) {
     use crate::lir::prelude::*;
 
-    let mut outbuffpos = 0;
+    // Loops being compiled, innermost last: each loop's end shift, offset
+    // of its cell, the rest of the IR around it, and that IR's LIR so far
+    let mut loops: Vec<(i32, i32, slice::Iter<IR>, LIRBuilder)> = Vec::new();
 
-    for i in ir {
-        match i {
-            IR::Output(value) => {
-                state
-                    .lir
-                    .mov(Buf("strbuf".into(), outbuffpos), value.clone());
-                outbuffpos += 1;
+    let mut rest = ir.iter();
+    loop {
+        let i = match rest.next() {
+            Some(i) => i,
+            None => {
+                state.outbuf.flush(&mut state.lir);
+                let (end_shift, start, outer_rest, outer) = match loops.pop() {
+                    Some(outer) => outer,
+                    None => return,
+                };
+                rest = outer_rest;
+                let mut body = mem::replace(&mut state.lir, outer).build();
+                state.offset += end_shift;
+                if state.offset != start {
+                    body.push(LIR::Shift(state.offset - start));
+                }
+                state.offset = start;
+                state.lir.loop_(Tape(start), body);
+                continue;
             }
+        };
+
+        match i {
+            IR::Output(value) => match value {
+                Immediate(_) => state.outbuf.push(value.clone()),
+                _ => {
+                    let value = match value {
+                        RVal::Tape(offset) => state.tape(*offset).into(),
+                        _ => value.clone(),
+                    };
+                    // Tape may change before output is flushed
+                    let reg = state.reg();
+                    state.lir.mov(Reg(reg), value);
+                    state.outbuf.push(Reg(reg).into());
+                }
+            },
             IR::Input(offset) => {
+                // Output before a read must be written before it
+                state.outbuf.flush(&mut state.lir);
                 state.lir.input("inputbuf", 0, 1);
-                state.lir.mov(Tape(*offset), Buf("inputbuf".into(), 0));
+                let tape = state.tape(*offset);
+                state.lir.mov(tape, Buf("inputbuf".into(), 0));
+            }
+            IR::Debug(offset) => {
+                // So the dump comes after any output before it
+                state.outbuf.flush(&mut state.lir);
+                state.lir.debug_dump(state.offset + offset);
             }
             IR::Loop(offset, inner, end_shift) => {
-                if outbuffpos != 0 {
-                    state.lir.output("strbuf", 0, outbuffpos);
-                    if state.outbuffsize < outbuffpos + 1 {
-                        state.outbuffsize = outbuffpos + 1;
-                    }
-                    outbuffpos = 0;
-                }
-
-                if *offset != 0 {
-                    state.lir.shift(*offset);
-                }
+                state.outbuf.flush(&mut state.lir);
 
-                state.loopnum += 1;
-                let startlabel = format!("loop{}", state.loopnum);
-                let endlabel = format!("endloop{}", state.loopnum);
-                state.lir.jp(endlabel.clone());
-                state.lir.label(startlabel.clone());
-
-                ir_to_lir_iter(state, inner);
-                if *end_shift != 0 {
-                    state.lir.shift(*end_shift);
+                // The cursor isn't moved to the loop's cell; instead, every
+                // iteration starts with it the same distance away, so a
+                // loop that ends where it started needs no shifts at all
+                state.offset += offset;
+                if !state.offsets {
+                    state.sync_cursor();
                 }
-
-                state.lir.label(endlabel.clone());
-                state.lir.jnz(Tape(0), startlabel.clone());
+                let outer = mem::take(&mut state.lir);
+                let outer_rest = mem::replace(&mut rest, inner.iter());
+                loops.push((*end_shift, state.offset, outer_rest, outer));
+            }
+            IR::Scan(offset, stride) => {
+                state.offset += offset;
+                state.sync_cursor();
+                state.lir.scan(*stride);
             }
             IR::Expr(expr) => {
                 let mut map: HashMap<_, RVal> = HashMap::new();
@@ -74,10 +125,11 @@ fn ir_to_lir_iter(state: &mut CompileState, ir: &[IR]) {
                         Value::Tape(offset) => {
                             if expr.contains_terminal(offset) {
                                 let reg = state.reg();
-                                state.lir.mov(Reg(reg), Tape(offset));
+                                let tape = state.tape(offset);
+                                state.lir.mov(Reg(reg), tape);
                                 map.insert(i, Reg(reg).into());
                             } else {
-                                map.insert(i, Tape(offset).into());
+                                map.insert(i, state.tape(offset).into());
                             };
                         }
                         Value::Const(value) => {
@@ -93,30 +145,35 @@ fn ir_to_lir_iter(state: &mut CompileState, ir: &[IR]) {
                             state.lir.add(Reg(reg), map[&a].clone(), map[&b].clone());
                             map.insert(i, Reg(reg).into());
                         }
+                        Value::Select(c, t, e) => {
+                            let reg = state.reg();
+                            state.lir.select(
+                                Reg(reg),
+                                map[&c].clone(),
+                                map[&t].clone(),
+                                map[&e].clone(),
+                            );
+                            map.insert(i, Reg(reg).into());
+                        }
                     }
                 }
 
                 for (k, v) in expr.terminals() {
-                    state.lir.mov(Tape(k), map[&v].clone());
+                    let tape = state.tape(k);
+                    state.lir.mov(tape, map[&v].clone());
                 }
             }
         }
     }
-
-    if outbuffpos != 0 {
-        state.lir.output("strbuf".to_string(), 0, outbuffpos);
-        if state.outbuffsize < outbuffpos + 1 {
-            state.outbuffsize = outbuffpos + 1;
-        }
-    }
 }
 
-pub fn ir_to_lir(ir: &[IR]) -> Vec<LIR> {
-    let mut state = CompileState::default();
+pub fn ir_to_lir(ir: &[IR], offsets: bool) -> Vec<LIR> {
+    let mut state = CompileState {
+        offsets,
+        ..Default::default()
+    };
     ir_to_lir_iter(&mut state, ir);
-    state
-        .lir
-        .declare_bss_buf("strbuf".to_string(), state.outbuffsize);
+    state.outbuf.declare(&mut state.lir);
     state.lir.declare_bss_buf("inputbuf".to_string(), 1);
 
     state.lir.build()
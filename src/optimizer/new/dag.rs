@@ -1,6 +1,11 @@
+use rustc_hash::FxHashMap;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-#[derive(Clone, Copy, Hash, Debug, PartialEq, Eq)]
+use super::known::KnownTape;
+use super::linear::{Linear, Term};
+
+#[derive(Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord)]
 /// An index of a node in a DAG
 pub struct Node(usize);
 
@@ -9,11 +14,35 @@ pub enum Value {
     /// The value of the tape at a given offset from the cursor
     Tape(i32),
     /// A constant value
-    Const(i32),
+    Const(i64),
     /// Multiply one DAG node with another
     Multiply(Node, Node),
     /// Add one DAG node to another
     Add(Node, Node),
+    /// The second node if the first is not zero, and the third otherwise
+    Select(Node, Node, Node),
+}
+
+impl Value {
+    /// The nodes this value reads
+    pub fn operands(self) -> impl Iterator<Item = Node> {
+        let operands = match self {
+            Value::Tape(_) | Value::Const(_) => [None, None, None],
+            Value::Multiply(l, r) | Value::Add(l, r) => [Some(l), Some(r), None],
+            Value::Select(c, t, e) => [Some(c), Some(t), Some(e)],
+        };
+        operands.into_iter().flatten()
+    }
+
+    /// This value with each node it reads replaced by *f* of it
+    pub fn map_operands(self, mut f: impl FnMut(Node) -> Node) -> Value {
+        match self {
+            Value::Tape(_) | Value::Const(_) => self,
+            Value::Multiply(l, r) => Value::Multiply(f(l), f(r)),
+            Value::Add(l, r) => Value::Add(f(l), f(r)),
+            Value::Select(c, t, e) => Value::Select(f(c), f(t), f(e)),
+        }
+    }
 }
 
 /*
@@ -42,14 +71,36 @@ impl fmt::Debug for Value {
 /// Code consisting only of shifts and adds can be reduced to a graph from
 /// tape offsets to tape offsets. Certain loops can also be transformed into a
 /// DAG.
-// TODO: try adding HashMap<Value, usize> for reverse node lookup;
-// see if this helps for efficiency.
-#[derive(Clone, Debug)]
+///
+/// Nodes are hash-consed, so each distinct value is stored once. Nodes are
+/// never removed until the DAG is cleared; ones no terminal depends on are
+/// skipped by `topological_sort`.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct DAG {
     nodes: Vec<Value>,
+    /// The node holding each value in `nodes`
+    lookup: FxHashMap<Value, Node>,
     terminals: HashMap<i32, Node>,
-    // TODO Should this be private?
-    pub zeroed: bool,
+    /// Terminals set since the last `simplify`
+    dirty: HashSet<i32>,
+    /// Canonical form of nodes `simplify` has seen, so values built on
+    /// earlier ones aren't canonicalized from scratch
+    canonical: FxHashMap<Node, Linear>,
+    /// Cells known before the DAG is evaluated. Reading one of these gives
+    /// a constant rather than `Value::Tape`.
+    known: KnownTape,
+}
+
+// The interner and caches only repeat what `nodes` says
+impl fmt::Debug for DAG {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DAG")
+            .field("nodes", &self.nodes)
+            .field("terminals", &self.terminals)
+            .field("known", &self.known)
+            .finish()
+    }
 }
 
 impl std::ops::Index<Node> for DAG {
@@ -61,11 +112,14 @@ impl std::ops::Index<Node> for DAG {
 }
 
 impl DAG {
-    pub fn new(zeroed: bool) -> Self {
+    pub fn new(known: KnownTape) -> Self {
         Self {
             nodes: Vec::new(),
+            lookup: FxHashMap::default(),
             terminals: HashMap::new(),
-            zeroed,
+            dirty: HashSet::new(),
+            canonical: FxHashMap::default(),
+            known,
         }
     }
 
@@ -74,26 +128,27 @@ impl DAG {
     }
 
     pub fn add_node(&mut self, value: Value) -> Node {
-        // TODO: efficiency
-        if let Some(pos) = self.nodes.iter().position(|x| *x == value) {
-            Node(pos)
+        if let Some(node) = self.lookup.get(&value) {
+            *node
         } else {
+            let node = Node(self.nodes.len());
             self.nodes.push(value);
-            Node(self.nodes.len() - 1)
+            self.lookup.insert(value, node);
+            node
         }
     }
 
     fn default_value(&self, offset: i32) -> Value {
-        if self.zeroed {
-            Value::Const(0)
-        } else {
-            Value::Tape(offset)
+        match self.known.get(offset) {
+            Some(value) => Value::Const(value),
+            None => Value::Tape(offset),
         }
     }
 
     pub fn set(&mut self, offset: i32, value: Value) {
         let node = self.add_node(value);
         self.terminals.insert(offset, node);
+        self.dirty.insert(offset);
     }
 
     pub fn get(&self, offset: i32) -> Value {
@@ -113,159 +168,226 @@ impl DAG {
         }
     }
 
-    pub fn add(&mut self, offset: i32, value: i32) {
+    pub fn add(&mut self, offset: i32, value: i64) {
         let old_node = self.get_node(offset);
         // Combine with existing add of constant
         if let Value::Add(lhs, rhs) = self[old_node] {
             if let Value::Const(old_value) = self[rhs] {
-                let new_node = self.add_node(Value::Const(old_value + value));
+                let new_node = self.add_node(Value::Const(old_value.wrapping_add(value)));
                 self.set(offset, Value::Add(lhs, new_node));
                 return;
             }
         } else if let Value::Const(old_value) = self[old_node] {
-            self.set(offset, Value::Const(old_value + value));
+            self.set(offset, Value::Const(old_value.wrapping_add(value)));
             return;
         }
         let new_node = self.add_node(Value::Const(value));
         self.set(offset, Value::Add(old_node, new_node));
     }
 
+    /// Copies *node* and the nodes it depends on from *other*
+    pub fn import(&mut self, other: &DAG, node: Node) -> Node {
+        let mut map = HashMap::new();
+        for i in other.sort_needed(vec![node]) {
+            let value = other[i].map_operands(|x| map[&x]);
+            map.insert(i, self.add_node(value));
+        }
+        map[&node]
+    }
+
     pub fn mul(&mut self, offset: i32, value: Value) {
         let old_node = self.get_node(offset);
         let new_node = self.add_node(value);
         self.set(offset, Value::Multiply(old_node, new_node));
     }
 
+    /// Removes all nodes, after the DAG has been emitted. Cells it sets to
+    /// constants remain known.
     pub fn clear(&mut self) {
+        self.known = self.known_after();
         self.nodes.clear();
+        self.lookup.clear();
+        self.dirty.clear();
+        self.canonical.clear();
         self.terminals.clear();
     }
 
+    /// Marks a cell as no longer known, such as after input is read into it.
+    /// Must only be called on an empty DAG.
+    pub fn forget(&mut self, offset: i32) {
+        debug_assert!(self.is_empty());
+        self.known.set(offset, None);
+    }
+
+    /// What is known about the tape after the DAG is evaluated
+    pub fn known_after(&self) -> KnownTape {
+        let mut known = self.known.clone();
+        for (k, v) in self.terminals() {
+            match self[v] {
+                Value::Const(value) => known.set(k, Some(value)),
+                _ => known.set(k, None),
+            }
+        }
+        known
+    }
+
     pub fn shift(&mut self, shift: i32) {
         let old_terminals = std::mem::take(&mut self.terminals);
         for (k, v) in old_terminals {
             self.terminals.insert(k + shift, v);
         }
+        let old_dirty = std::mem::take(&mut self.dirty);
+        self.dirty = old_dirty.into_iter().map(|k| k + shift).collect();
+        self.known.shift(shift);
+        self.canonical.clear();
         for i in self.nodes.iter_mut() {
             if let Value::Tape(offset) = *i {
                 *i = Value::Tape(offset + shift);
             }
         }
+        self.lookup = (self.nodes.iter().enumerate())
+            .map(|(i, value)| (*value, Node(i)))
+            .collect();
     }
 
     pub fn contains_terminal(&self, index: i32) -> bool {
         self.terminals.contains_key(&index)
     }
 
+    /// Nodes the terminals depend on, in an order where each node comes
+    /// after the nodes it depends on. Nodes nothing needs are skipped.
     pub fn topological_sort(&self) -> impl Iterator<Item = Node> {
-        // Assumes nodes are never deleted, so numberic order is toplogical
-        // TODO: doesn't skip unneeded nodes
-        (0..self.nodes.len()).map(Node)
+        self.sort_needed(self.terminals.values().cloned().collect())
+    }
+
+    /// *roots* and the nodes they depend on, in topological order
+    fn sort_needed(&self, mut roots: Vec<Node>) -> impl Iterator<Item = Node> {
+        let mut needed = vec![false; self.nodes.len()];
+        while let Some(node) = roots.pop() {
+            if needed[node.0] {
+                continue;
+            }
+            needed[node.0] = true;
+            roots.extend(self[node].operands());
+        }
+        // A node is only added after the nodes it depends on, so numeric
+        // order is topological
+        (0..self.nodes.len()).filter(move |i| needed[*i]).map(Node)
+    }
+
+    /// A copy of this DAG without the nodes no terminal depends on
+    pub fn compact(&self) -> DAG {
+        let mut dag = DAG::new(self.known.clone());
+        dag.dirty = self.dirty.clone();
+        // Node in `dag` for each needed node of `self`
+        let mut map = HashMap::new();
+        for node in self.topological_sort() {
+            let value = self[node].map_operands(|x| map[&x]);
+            map.insert(node, dag.add_node(value));
+        }
+        for (k, v) in self.terminals() {
+            dag.terminals.insert(k, map[&v]);
+        }
+        dag
     }
 
     pub fn terminals<'a>(&'a self) -> impl Iterator<Item = (i32, Node)> + 'a {
         self.terminals.iter().map(|(k, v)| (*k, *v))
     }
 
-    pub fn extend(&mut self, mut expr: DAG) {
-        for i in expr.terminals.values_mut() {
-            i.0 += self.nodes.len();
+    /// Appends *expr*, to be evaluated after this DAG
+    pub fn extend(&mut self, expr: DAG) {
+        // Node in `self` for each node of `expr`
+        let mut map = Vec::with_capacity(expr.nodes.len());
+        for value in &expr.nodes {
+            let node = match *value {
+                Value::Tape(offset) => match self.terminals.get(&offset) {
+                    Some(node) => *node,
+                    None => self.add_node(self.default_value(offset)),
+                },
+                _ => self.add_node(value.map_operands(|x| map[x.0])),
+            };
+            map.push(node);
         }
 
-        for i in &mut expr.nodes {
-            match i {
-                Value::Tape(offset) => {
-                    if let Some(node) = self.terminals.get(offset) {
-                        // XXX
-                        *i = self[*node];
-                    }
-                }
-                Value::Const(_) => {}
-                Value::Add(lhs, rhs) | Value::Multiply(lhs, rhs) => {
-                    lhs.0 += self.nodes.len();
-                    rhs.0 += self.nodes.len();
-                }
-            }
+        for (k, v) in expr.terminals {
+            self.terminals.insert(k, map[v.0]);
+            self.dirty.insert(k);
         }
-
-        self.terminals.extend(expr.terminals);
-        self.nodes.extend(expr.nodes);
     }
 
+    /// Rewrites every value set since the last call in canonical form, as
+    /// a linear combination of tape cells plus a constant, so constants are
+    /// folded, like terms are combined, and equal values share a node. See
+    /// `Linear`.
     pub fn simplify(&mut self) {
-        fn simplify_iter(dag: &mut DAG, old_nodes: &[Value], node: Node) -> Value {
-            let value = old_nodes[node.0];
-            match value {
-                Value::Tape(_) | Value::Const(_) => value,
+        fn simplify_iter(dag: &mut DAG, cache: &mut FxHashMap<Node, Linear>, node: Node) -> Linear {
+            if let Some(linear) = cache.get(&node) {
+                return linear.clone();
+            }
+            let linear = match dag[node] {
+                Value::Tape(offset) => Linear::term(Term::Tape(offset)),
+                Value::Const(value) => Linear::constant(value),
                 Value::Multiply(l, r) => {
-                    let lhs = simplify_iter(dag, old_nodes, l);
-                    let rhs = simplify_iter(dag, old_nodes, r);
-                    match (lhs, rhs) {
-                        (Value::Const(a), Value::Const(b)) => Value::Const(a * b),
-                        (Value::Const(0), _) | (_, Value::Const(0)) => Value::Const(0),
-                        (Value::Const(1), val) | (val, Value::Const(1)) => val,
-                        _ => {
-                            let l = dag.add_node(lhs);
-                            let r = dag.add_node(rhs);
-                            Value::Multiply(l, r)
-                        }
-                    }
+                    let lhs = simplify_iter(dag, cache, l);
+                    let rhs = simplify_iter(dag, cache, r);
+                    lhs.mul(rhs, dag)
                 }
                 Value::Add(l, r) => {
-                    let lhs = simplify_iter(dag, old_nodes, l);
-                    let rhs = simplify_iter(dag, old_nodes, r);
-                    match (lhs, rhs) {
-                        (Value::Const(a), Value::Const(b)) => Value::Const(a + b),
-                        (Value::Const(0), val) | (val, Value::Const(0)) => val,
-                        _ => {
-                            let l = dag.add_node(lhs);
-                            let r = dag.add_node(rhs);
-                            Value::Add(l, r)
-                        }
-                    }
+                    let lhs = simplify_iter(dag, cache, l);
+                    let rhs = simplify_iter(dag, cache, r);
+                    lhs.add(&rhs)
                 }
-            }
+                Value::Select(c, t, e) => {
+                    let cond = simplify_iter(dag, cache, c);
+                    let then = simplify_iter(dag, cache, t);
+                    let else_ = simplify_iter(dag, cache, e);
+                    cond.select(then, else_, dag)
+                }
+            };
+            cache.insert(node, linear.clone());
+            linear
         }
 
-        let old_terminals = std::mem::take(&mut self.terminals);
-        let old_nodes = std::mem::take(&mut self.nodes);
-
-        for (k, v) in old_terminals {
-            let value = simplify_iter(self, &old_nodes, v);
-            if value != Value::Tape(k) {
-                self.set(k, value);
+        let mut cache = std::mem::take(&mut self.canonical);
+        for k in std::mem::take(&mut self.dirty) {
+            let node = match self.terminals.get(&k) {
+                Some(node) => *node,
+                None => continue,
+            };
+            let linear = simplify_iter(self, &mut cache, node);
+            let value = linear.build(self);
+            if value == self.default_value(k) {
+                self.terminals.remove(&k);
+            } else {
+                let node = self.add_node(value);
+                self.terminals.insert(k, node);
+                cache.insert(node, linear);
             }
         }
-
-        //if old_nodes.len() != self.nodes.len() {
-        //    println!("{} -> {}", old_nodes.len(), self.nodes.len());
-        //}
+        self.canonical = cache;
     }
 
-    // TODO efficiency
+    /// Offsets of the cells *node* reads
     pub fn dependencies(&self, node: Node) -> HashSet<i32> {
-        fn dependencies_iter(dag: &DAG, set: &mut HashSet<i32>, node: Node) {
-            match dag[node] {
+        let mut set = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node) {
+                continue;
+            }
+            match self[node] {
                 Value::Tape(offset) => {
                     set.insert(offset);
                 }
-                Value::Const(_) => {}
-                Value::Multiply(l, r) => {
-                    dependencies_iter(dag, set, l);
-                    dependencies_iter(dag, set, r);
-                }
-                Value::Add(l, r) => {
-                    dependencies_iter(dag, set, l);
-                    dependencies_iter(dag, set, r);
-                }
+                value => stack.extend(value.operands()),
             }
         }
-        let mut set = HashSet::new();
-        dependencies_iter(self, &mut set, node);
         set
     }
 
-    pub fn as_add_const(&self, offset: i32) -> Option<i32> {
+    pub fn as_add_const(&self, offset: i32) -> Option<i64> {
         if let Value::Add(lhs, rhs) = self.get(offset) {
             match (self[lhs], self[rhs]) {
                 (Value::Tape(off), Value::Const(a)) if off == offset => Some(a),
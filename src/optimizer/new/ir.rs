@@ -1,10 +1,33 @@
+use std::mem;
+
 use super::dag::DAG;
 use crate::lir::RVal;
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum IR {
     Output(RVal),
     Input(i32),
+    /// `Debug(offset)` Writes the cells around the cell at *offset* to
+    /// stderr
+    Debug(i32),
     Loop(i32, Vec<IR>, i32),
+    /// `Scan(offset, stride)` Shifts by *offset*, then by *stride* until
+    /// the current cell is zero
+    Scan(i32, i32),
     Expr(DAG),
 }
+
+impl Drop for IR {
+    /// Like `AST`, drops nested loops without recursing
+    fn drop(&mut self) {
+        if let IR::Loop(_, body, _) = self {
+            let mut stack = mem::take(body);
+            while let Some(mut ir) = stack.pop() {
+                if let IR::Loop(_, body, _) = &mut ir {
+                    stack.append(body);
+                }
+            }
+        }
+    }
+}
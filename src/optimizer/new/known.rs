@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+/// What is known at compile time about the values of tape cells, by offset
+/// from the cursor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KnownTape {
+    /// The value of every cell not in `cells`, if known
+    default: Option<i64>,
+    cells: HashMap<i32, Option<i64>>,
+}
+
+impl KnownTape {
+    /// Nothing is known about any cell
+    pub fn unknown() -> Self {
+        Self::default()
+    }
+
+    /// Every cell is zero, as at the start of a program
+    pub fn zeroed() -> Self {
+        Self {
+            default: Some(0),
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, offset: i32) -> Option<i64> {
+        self.cells.get(&offset).cloned().unwrap_or(self.default)
+    }
+
+    /// Adds *shift* to the offset of every cell
+    pub fn shift(&mut self, shift: i32) {
+        let old_cells = std::mem::take(&mut self.cells);
+        for (k, v) in old_cells {
+            self.cells.insert(k + shift, v);
+        }
+    }
+
+    /// Only what is known in both *self* and *other*
+    pub fn meet(&self, other: &KnownTape) -> KnownTape {
+        let mut known = KnownTape {
+            default: if self.default == other.default {
+                self.default
+            } else {
+                None
+            },
+            cells: HashMap::new(),
+        };
+        for offset in self.cells.keys().chain(other.cells.keys()) {
+            let value = self.get(*offset);
+            known.set(
+                *offset,
+                if value == other.get(*offset) {
+                    value
+                } else {
+                    None
+                },
+            );
+        }
+        known
+    }
+
+    pub fn set(&mut self, offset: i32, value: Option<i64>) {
+        if value == self.default {
+            self.cells.remove(&offset);
+        } else {
+            self.cells.insert(offset, value);
+        }
+    }
+}
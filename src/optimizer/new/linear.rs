@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+
+use super::dag::{Node, Value, DAG};
+
+/// A term of a `Linear`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Term {
+    /// The value of the tape at a given offset
+    Tape(i32),
+    /// A product of two nodes that doesn't reduce to a linear combination.
+    /// The lower node comes first.
+    Product(Node, Node),
+    /// A select whose condition isn't constant, and whose branches differ
+    Select(Node, Node, Node),
+}
+
+/// A canonical form of a DAG value: a sum of terms, each multiplied by a
+/// coefficient, plus a constant. Terms are sorted, so equal combinations
+/// build equal nodes.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct Linear {
+    terms: BTreeMap<Term, i64>,
+    constant: i64,
+}
+
+impl Linear {
+    pub fn constant(value: i64) -> Self {
+        Self {
+            terms: BTreeMap::new(),
+            constant: value,
+        }
+    }
+
+    pub fn term(term: Term) -> Self {
+        let mut terms = BTreeMap::new();
+        terms.insert(term, 1);
+        Self { terms, constant: 0 }
+    }
+
+    fn as_constant(&self) -> Option<i64> {
+        if self.terms.is_empty() {
+            Some(self.constant)
+        } else {
+            None
+        }
+    }
+
+    /// The single term, and its coefficient, if that is all there is
+    fn as_term(&self) -> Option<(Term, i64)> {
+        if self.terms.len() == 1 && self.constant == 0 {
+            self.terms.iter().next().map(|(t, c)| (*t, *c))
+        } else {
+            None
+        }
+    }
+
+    pub fn add(mut self, other: &Linear) -> Self {
+        for (term, coefficient) in &other.terms {
+            let sum = self
+                .terms
+                .get(term)
+                .unwrap_or(&0)
+                .wrapping_add(*coefficient);
+            if sum == 0 {
+                self.terms.remove(term);
+            } else {
+                self.terms.insert(*term, sum);
+            }
+        }
+        self.constant = self.constant.wrapping_add(other.constant);
+        self
+    }
+
+    fn scale(mut self, factor: i64) -> Self {
+        if factor == 0 {
+            return Self::default();
+        }
+        for coefficient in self.terms.values_mut() {
+            *coefficient = coefficient.wrapping_mul(factor);
+        }
+        self.terms.retain(|_, coefficient| *coefficient != 0);
+        self.constant = self.constant.wrapping_mul(factor);
+        self
+    }
+
+    /// Multiplies by *other*, adding nodes to *dag* for any product that
+    /// has to be kept as a term
+    pub fn mul(self, other: Linear, dag: &mut DAG) -> Self {
+        if let Some(factor) = other.as_constant() {
+            self.scale(factor)
+        } else if let Some(factor) = self.as_constant() {
+            other.scale(factor)
+        } else if let (Some((a, a_coef)), Some((b, b_coef))) = (self.as_term(), other.as_term()) {
+            // Keep the coefficients outside the product
+            let a = term_node(a, dag);
+            let b = term_node(b, dag);
+            Self::term(Term::Product(a.min(b), a.max(b))).scale(a_coef.wrapping_mul(b_coef))
+        } else {
+            let a = self.build_node(dag);
+            let b = other.build_node(dag);
+            Self::term(Term::Product(a.min(b), a.max(b)))
+        }
+    }
+
+    /// *then* if this is not zero, otherwise *else_*, adding nodes to *dag*
+    /// if that can't be decided now
+    pub fn select(self, then: Linear, else_: Linear, dag: &mut DAG) -> Self {
+        // The cell size isn't known here, so only constants that are zero,
+        // or nonzero, in every cell size can be decided
+        match self.as_constant() {
+            Some(0) => else_,
+            Some(value) if value & 0xff != 0 => then,
+            _ if then == else_ => then,
+            _ => {
+                let cond = self.build_node(dag);
+                Self::term(Term::Select(
+                    cond,
+                    then.build_node(dag),
+                    else_.build_node(dag),
+                ))
+            }
+        }
+    }
+
+    /// The value this represents, with any nodes it depends on added to
+    /// *dag*
+    pub fn build(&self, dag: &mut DAG) -> Value {
+        let mut values = Vec::new();
+        for (term, coefficient) in &self.terms {
+            let value = term_value(*term);
+            values.push(if *coefficient == 1 {
+                value
+            } else {
+                let lhs = dag.add_node(value);
+                let rhs = dag.add_node(Value::Const(*coefficient));
+                Value::Multiply(lhs, rhs)
+            });
+        }
+        if self.constant != 0 || values.is_empty() {
+            values.push(Value::Const(self.constant));
+        }
+
+        let mut values = values.into_iter();
+        let mut sum = values.next().unwrap();
+        for value in values {
+            let lhs = dag.add_node(sum);
+            let rhs = dag.add_node(value);
+            sum = Value::Add(lhs, rhs);
+        }
+        sum
+    }
+
+    fn build_node(&self, dag: &mut DAG) -> Node {
+        let value = self.build(dag);
+        dag.add_node(value)
+    }
+}
+
+fn term_value(term: Term) -> Value {
+    match term {
+        Term::Tape(offset) => Value::Tape(offset),
+        Term::Product(a, b) => Value::Multiply(a, b),
+        Term::Select(c, t, e) => Value::Select(c, t, e),
+    }
+}
+
+fn term_node(term: Term, dag: &mut DAG) -> Node {
+    dag.add_node(term_value(term))
+}
@@ -2,7 +2,8 @@
 
 #![allow(dead_code)]
 
-use super::Optimizer;
+use super::{Optimizer, Pass, PassConfig};
+use crate::lir::CellType;
 use crate::{AST, LIR};
 use std::io::Write;
 
@@ -10,17 +11,74 @@ mod compile;
 mod dag;
 use compile::ir_to_lir;
 mod ir;
+mod known;
+mod linear;
 mod optimize;
 use optimize::optimize;
 
 pub struct NewOptimizer;
 
+const PASSES: &[Pass] = &[
+    Pass {
+        name: "simplify",
+        level: 1,
+        description: "fold constants and drop dead values in expressions",
+    },
+    Pass {
+        name: "scan",
+        level: 1,
+        description: "turn loops that only move the cursor into scans",
+    },
+    Pass {
+        name: "counter-loops",
+        level: 1,
+        description: "turn loops with a fixed step into expressions",
+    },
+    Pass {
+        name: "if-loops",
+        level: 2,
+        description: "turn loops that run at most once into selects",
+    },
+    Pass {
+        name: "known",
+        level: 2,
+        description: "use values known before a loop in its body, up to 3 loops deep (8 at -O3)",
+    },
+    Pass {
+        name: "offsets",
+        level: 2,
+        description: "keep the cursor in place across loops",
+    },
+];
+
+/// Passes run while optimizing the AST to IR, as opposed to compiling it
+const OPTIMIZE_PASSES: &[&str] = &["simplify", "scan", "counter-loops", "if-loops", "known"];
+
+fn optimize_dumping(ast: &[AST], passes: &PassConfig, cell: CellType) -> Vec<ir::IR> {
+    let ir = optimize(ast, cell, passes);
+    passes.dump(OPTIMIZE_PASSES, &ir);
+    ir
+}
+
 impl Optimizer for NewOptimizer {
-    fn optimize(&self, ast: &[AST], _level: u32) -> Vec<LIR> {
-        ir_to_lir(&optimize(ast))
+    fn passes(&self) -> &'static [Pass] {
+        PASSES
+    }
+
+    fn optimize(&self, ast: &[AST], passes: &PassConfig, cell: CellType) -> Vec<LIR> {
+        let ir = optimize_dumping(ast, passes, cell);
+        let lir = ir_to_lir(&ir, passes.enabled("offsets"));
+        passes.dump(&["offsets"], &lir);
+        lir
     }
 
-    fn dumpir(&self, ast: &[AST], _level: u32, file: &mut dyn Write) -> std::io::Result<()> {
-        write!(file, "{:#?}", optimize(ast))
+    fn dumpir(
+        &self,
+        ast: &[AST],
+        passes: &PassConfig,
+        cell: CellType,
+        file: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        write!(file, "{:#?}", optimize_dumping(ast, passes, cell))
     }
 }
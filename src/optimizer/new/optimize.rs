@@ -1,100 +1,501 @@
-use super::dag::{Value, DAG};
+use std::collections::HashSet;
+use std::mem;
+use std::slice;
+
+use super::dag::{Node, Value, DAG};
 use super::ir::IR;
-use crate::lir::RVal;
+use super::known::KnownTape;
+use crate::lir::{CellType, RVal};
+use crate::optimizer::counter::{trip_count, trip_multiplier};
+use crate::optimizer::{LoopRemark, PassConfig};
 use crate::AST;
 
-pub fn optimize(body: &[AST]) -> Vec<IR> {
-    optimize_expr(body).0
+pub fn optimize(body: &[AST], cell: CellType, passes: &PassConfig) -> Vec<IR> {
+    // The tape starts zeroed
+    optimize_expr(body, KnownTape::zeroed(), cell, passes)
+}
+
+/// A body being optimized: the rest of it, and its IR so far
+struct Body<'a> {
+    rest: slice::Iter<'a, AST>,
+    ir: Vec<IR>,
+    expr: DAG,
+    shift: i32,
 }
 
-fn optimize_expr(body: &[AST]) -> (Vec<IR>, i32) {
-    let mut ir = Vec::new();
+/// A loop whose body is being optimized, in the body around it
+struct LoopBody<'a> {
+    body: &'a [AST],
+    id: usize,
+    /// Value of the loop's cell before it, if known
+    counter: Option<i64>,
+    /// Number of the first loop in *body*
+    first_loop: usize,
+    /// What is assumed known at the start of every iteration, while
+    /// looking for a fixpoint of that; `None` once nothing is assumed
+    known: Option<KnownTape>,
+}
+
+/// Optimizes *body*, given what is known about the tape before it.
+///
+/// Loop bodies are optimized on an explicit stack, rather than
+/// recursively, so deeply nested programs can't overflow the stack. Loops
+/// are numbered in pre-order, for remarks.
+fn optimize_expr(body: &[AST], known: KnownTape, cell: CellType, passes: &PassConfig) -> Vec<IR> {
+    let simplify = passes.enabled("simplify");
+    let max_depth = passes.known_depth();
+    let mut next_loop = 0;
+    // Bodies around the one being optimized, innermost last, each with
+    // the loop it is waiting on
+    let mut stack: Vec<(Body, LoopBody)> = Vec::new();
+
+    let mut current = Body::new(body, known);
+    loop {
+        let i = match current.rest.next() {
+            Some(i) => i,
+            None => {
+                let (ir, shift, after) = current.finish(simplify);
+                let (outer, mut loop_body) = match stack.pop() {
+                    Some(outer) => outer,
+                    None => return ir,
+                };
+                if let Some(known) = &loop_body.known {
+                    // The body is optimized again, with less assumed, until
+                    // what it leaves known includes what was assumed. If
+                    // it doesn't end where it started, nothing is assumed.
+                    let next = if net_shift(&ir, shift) == Some(0) {
+                        let mut next = known.meet(&after);
+                        next.set(0, None);
+                        Some(next)
+                    } else {
+                        None
+                    };
+                    if next.as_ref() != Some(known) {
+                        next_loop = loop_body.first_loop;
+                        let known = next.clone().unwrap_or_else(KnownTape::unknown);
+                        current = Body::new(loop_body.body, known);
+                        loop_body.known = next;
+                        stack.push((outer, loop_body));
+                        continue;
+                    }
+                }
+                current = outer;
+                // Only the loop's cell, and whatever holds at the start of
+                // every iteration, are known after it exits
+                let mut known_each = loop_body.known.unwrap_or_else(KnownTape::unknown);
+                known_each.set(0, Some(0));
+                current.end_loop(
+                    loop_body.id,
+                    loop_body.counter,
+                    ir,
+                    shift,
+                    known_each,
+                    cell,
+                    passes,
+                );
+                continue;
+            }
+        };
 
-    // TODO zeroing
-    let mut expr = DAG::new(false);
-    let mut shift = 0;
-    for i in body {
         match i {
             AST::Input => {
-                ir.push(IR::Input(shift));
-                expr.set(shift, Value::Tape(shift));
+                if simplify {
+                    current.expr.simplify();
+                }
+                current.push_expr();
+                current.expr.forget(current.shift);
+                current.ir.push(IR::Input(current.shift));
+            }
+            AST::Debug => {
+                if simplify {
+                    current.expr.simplify();
+                }
+                // The tape is read as it is, so it has to be written first
+                current.push_expr();
+                current.ir.push(IR::Debug(current.shift));
             }
             AST::Output => {
-                if let Value::Const(value) = expr.get(shift) {
-                    ir.push(IR::Output(RVal::Immediate(value)));
+                if simplify {
+                    current.expr.simplify();
+                }
+                if let Value::Const(value) = current.expr.get(current.shift) {
+                    current.ir.push(IR::Output(RVal::Immediate(value)));
                 } else {
-                    if !expr.is_empty() {
-                        ir.push(IR::Expr(expr.clone()));
-                    }
-                    expr.clear();
-                    ir.push(IR::Output(RVal::Tape(shift)));
+                    current.push_expr();
+                    current.ir.push(IR::Output(RVal::Tape(current.shift)));
                 }
             }
             AST::Loop(body) => {
-                expr.simplify();
-                let (loop_body, loop_shift) = optimize_expr(body);
-                if loop_body.len() == 1 && loop_shift == 0 {
-                    if let IR::Expr(ref loop_expr) = loop_body[0] {
-                        if let Some(mut new_expr) = optimize_expr_loop(&loop_expr) {
-                            new_expr.shift(shift);
-                            expr.extend(new_expr);
-                            continue;
-                        }
+                let id = next_loop;
+                next_loop += 1;
+                if simplify {
+                    current.expr.simplify();
+                }
+                let counter = match current.expr.get(current.shift) {
+                    Value::Const(value) => Some(value),
+                    _ => None,
+                };
+                if counter.map(|x| cell.wrap(x)) == Some(0) {
+                    // Loop never runs
+                    passes.remark(id, LoopRemark::Removed);
+                    let nested = count_loops(body);
+                    for nested_id in next_loop..next_loop + nested {
+                        passes.remark(nested_id, LoopRemark::RemovedWithEnclosing);
                     }
+                    next_loop += nested;
+                    continue;
                 }
-                ir.push(IR::Expr(expr.clone()));
-                expr.clear();
-                expr.zeroed = false;
-                ir.push(IR::Loop(shift, loop_body, loop_shift));
-                shift = 0;
+                // Shallow bodies are optimized with what is known before
+                // the loop, except for the loop's cell
+                let known = if passes.enabled("known") && depth_at_most(body, max_depth) {
+                    let mut known = current.expr.known_after();
+                    known.shift(-current.shift);
+                    known.set(0, None);
+                    Some(known)
+                } else {
+                    None
+                };
+                let inner = Body::new(body, known.clone().unwrap_or_else(KnownTape::unknown));
+                let loop_body = LoopBody {
+                    body,
+                    id,
+                    counter,
+                    first_loop: next_loop,
+                    known,
+                };
+                stack.push((mem::replace(&mut current, inner), loop_body));
             }
             AST::Shift(offset) => {
-                shift += offset;
+                current.shift += offset;
             }
             AST::Add(add) => {
-                expr.add(shift, *add);
+                current.expr.add(current.shift, i64::from(*add));
             }
         }
     }
+}
 
-    expr.simplify();
-    if !expr.is_empty() {
-        ir.push(IR::Expr(expr.clone()));
+impl<'a> Body<'a> {
+    fn new(body: &'a [AST], known: KnownTape) -> Self {
+        Self {
+            rest: body.iter(),
+            ir: Vec::new(),
+            expr: DAG::new(known),
+            shift: 0,
+        }
     }
 
-    (ir, shift)
+    /// Emits the pending expression, and starts a new one from what is
+    /// known after it
+    fn push_expr(&mut self) {
+        if !self.expr.is_empty() {
+            self.ir.push(IR::Expr(self.expr.compact()));
+        }
+        self.expr.clear();
+    }
+
+    /// Returns the IR, the shift at the end, and what is known at the end
+    fn finish(mut self, simplify: bool) -> (Vec<IR>, i32, KnownTape) {
+        if simplify {
+            self.expr.simplify();
+        }
+        if !self.expr.is_empty() {
+            self.ir.push(IR::Expr(self.expr.compact()));
+        }
+
+        // Relative to the cursor at the end, rather than where `expr` starts
+        let mut known = self.expr.known_after();
+        known.shift(-self.shift);
+        (self.ir, self.shift, known)
+    }
+
+    /// Emits loop *id*, or what it was optimized to, given its optimized
+    /// body and what is known at the start of every iteration
+    #[allow(clippy::too_many_arguments)]
+    fn end_loop(
+        &mut self,
+        id: usize,
+        counter: Option<i64>,
+        mut loop_body: Vec<IR>,
+        loop_shift: i32,
+        known_each: KnownTape,
+        cell: CellType,
+        passes: &PassConfig,
+    ) {
+        if passes.enabled("scan") && loop_body.is_empty() && loop_shift != 0 {
+            if !self.expr.is_empty() {
+                self.ir.push(IR::Expr(self.expr.compact()));
+            }
+            self.ir.push(IR::Scan(self.shift, loop_shift));
+            passes.remark(id, LoopRemark::Scan);
+            self.shift = 0;
+            // Where the scan stops is only known to be zero
+            let mut known = KnownTape::unknown();
+            known.set(0, Some(0));
+            self.expr = DAG::new(known);
+            return;
+        }
+        if let ([IR::Expr(loop_expr)], 0) = (&loop_body[..], loop_shift) {
+            match optimize_expr_loop(loop_expr, counter, cell, passes) {
+                Ok(LoopForm::Flat(mut new_expr)) => {
+                    new_expr.shift(self.shift);
+                    self.expr.extend(new_expr);
+                    passes.remark(id, LoopRemark::Expr);
+                    return;
+                }
+                Ok(LoopForm::Infinite) => {
+                    loop_body.clear();
+                    passes.remark(id, LoopRemark::Infinite);
+                }
+                Err(reason) => passes.remark(id, LoopRemark::Loop(reason)),
+            }
+        } else {
+            let reason = not_expr_reason(&loop_body, loop_shift);
+            passes.remark(id, LoopRemark::Loop(reason));
+        }
+        if !self.expr.is_empty() {
+            self.ir.push(IR::Expr(self.expr.compact()));
+        }
+        self.ir.push(IR::Loop(self.shift, loop_body, loop_shift));
+        self.shift = 0;
+        self.expr = DAG::new(known_each);
+    }
+}
+
+/// Whether *body* has no loops nested more than *max* deep in it
+fn depth_at_most(body: &[AST], max: usize) -> bool {
+    // Loop bodies left to check, with how deep the loops in them are
+    let mut bodies = vec![(body, 1)];
+    while let Some((body, depth)) = bodies.pop() {
+        for i in body {
+            if let AST::Loop(inner) = i {
+                if depth > max {
+                    return false;
+                }
+                bodies.push((inner, depth + 1));
+            }
+        }
+    }
+    true
+}
+
+fn count_loops(body: &[AST]) -> usize {
+    let mut count = 0;
+    let mut bodies = vec![body];
+    while let Some(body) = bodies.pop() {
+        for i in body {
+            if let AST::Loop(inner) = i {
+                count += 1;
+                bodies.push(inner);
+            }
+        }
+    }
+    count
+}
+
+/// Why a loop with body *ir*, followed by a shift of *shift*, isn't a
+/// single expression that `optimize_expr_loop` can flatten
+fn not_expr_reason(ir: &[IR], shift: i32) -> String {
+    if ir
+        .iter()
+        .any(|i| matches!(i, IR::Input(_) | IR::Output(_) | IR::Debug(_)))
+    {
+        "body contains I/O".to_string()
+    } else if ir.iter().any(|i| matches!(i, IR::Loop(..) | IR::Scan(..))) {
+        "body contains a loop".to_string()
+    } else if ir.is_empty() && shift != 0 {
+        "scan pass is disabled".to_string()
+    } else if shift != 0 {
+        format!("body moves the cursor by {}", shift)
+    } else {
+        "body is empty".to_string()
+    }
+}
+
+/// How far *ir*, followed by a shift of *shift*, moves the cursor, or
+/// `None` if that depends on the tape
+fn net_shift(ir: &[IR], shift: i32) -> Option<i32> {
+    let mut net = shift;
+    for i in ir {
+        match i {
+            IR::Loop(offset, body, end_shift) => {
+                if net_shift(body, *end_shift) != Some(0) {
+                    return None;
+                }
+                net += offset;
+            }
+            IR::Scan(..) => return None,
+            _ => {}
+        }
+    }
+    Some(net)
+}
+
+/// What a loop can be replaced with
+#[allow(clippy::large_enum_variant)]
+enum LoopForm {
+    /// The loop always terminates, with the same effect as this DAG
+    Flat(DAG),
+    /// The loop never terminates, so its body can be dropped
+    Infinite,
+}
+
+/// If *node* of *body_expr* is the value of the cell at *offset* plus
+/// terms that don't depend on any cell in *changed*, returns those terms.
+fn increment(
+    body_expr: &DAG,
+    node: Node,
+    offset: i32,
+    changed: &HashSet<i32>,
+) -> Option<Vec<Node>> {
+    fn increment_iter(
+        body_expr: &DAG,
+        node: Node,
+        offset: i32,
+        changed: &HashSet<i32>,
+        found: &mut bool,
+        terms: &mut Vec<Node>,
+    ) -> bool {
+        match body_expr[node] {
+            Value::Tape(off) if off == offset && !*found => {
+                *found = true;
+                true
+            }
+            Value::Add(l, r) => {
+                increment_iter(body_expr, l, offset, changed, found, terms)
+                    && increment_iter(body_expr, r, offset, changed, found, terms)
+            }
+            _ => {
+                terms.push(node);
+                body_expr.dependencies(node).is_disjoint(changed)
+            }
+        }
+    }
+
+    let mut found = false;
+    let mut terms = Vec::new();
+    if increment_iter(body_expr, node, offset, changed, &mut found, &mut terms) && found {
+        Some(terms)
+    } else {
+        None
+    }
+}
+
+/// Given a loop whose body is a single DAG that clears the loop's cell,
+/// so it runs at most once, a DAG with the same effect. Unless *counter*
+/// is known, each change the body makes is a select on whether it runs.
+fn optimize_if_loop(body_expr: &DAG, counter: Option<i64>) -> DAG {
+    if counter.is_some() {
+        // Known not to be zero, so the body runs exactly once
+        return body_expr.clone();
+    }
+
+    let mut expr = DAG::new(KnownTape::unknown());
+    let cond = expr.add_node(Value::Tape(0));
+    // Zero whether or not the body runs
+    expr.set(0, Value::Const(0));
+    for (k, v) in body_expr.terminals() {
+        if k == 0 || body_expr[v] == Value::Tape(k) {
+            continue;
+        }
+        let then = expr.import(body_expr, v);
+        let else_ = expr.add_node(Value::Tape(k));
+        expr.set(k, Value::Select(cond, then, else_));
+    }
+    expr
 }
 
 /// Given a loop with no end shift, where the body is a single DAG,
 /// if possible optimize such that the loop is replaced with a flat
-/// DAG.
-fn optimize_expr_loop(body_expr: &DAG) -> Option<DAG> {
-    // TODO: Generalize constants to any tape offset unchange in DAG
+/// DAG. *counter* is the value of the loop's cell, if known.
+///
+/// Other cells the body changes must either be incremented by a value
+/// that is the same every iteration, which gives a product with the
+/// number of iterations, or be set to such a value. Otherwise, returns why
+/// the loop can't be replaced.
+fn optimize_expr_loop(
+    body_expr: &DAG,
+    counter: Option<i64>,
+    cell: CellType,
+    passes: &PassConfig,
+) -> Result<LoopForm, String> {
+    let if_loops = passes.enabled("if-loops");
+    if let Value::Const(value) = body_expr.get(0) {
+        if if_loops && cell.wrap(value) == 0 {
+            return Ok(LoopForm::Flat(optimize_if_loop(body_expr, counter)));
+        }
+    }
 
-    if body_expr.as_add_const(0) != Some(-1) {
-        return None;
+    if !passes.enabled("counter-loops") {
+        return Err("counter-loops pass is disabled".to_string());
     }
+    let step = (body_expr.as_add_const(0))
+        .ok_or_else(|| "counter isn't changed by a constant".to_string())?;
+
+    let mut expr = DAG::new(KnownTape::unknown());
+
+    // Number of times the loop runs, either as a constant or as a multiple
+    // of the counter's starting value
+    let count = match counter {
+        Some(counter) => match trip_count(counter, step, cell) {
+            Some(count) => expr.add_node(Value::Const(count)),
+            None => return Ok(LoopForm::Infinite),
+        },
+        None => {
+            let lhs = expr.add_node(Value::Tape(0));
+            let multiplier =
+                (trip_multiplier(step, cell)).ok_or_else(|| format!("counter step is {}", step))?;
+            let rhs = expr.add_node(Value::Const(multiplier));
+            expr.add_node(Value::Multiply(lhs, rhs))
+        }
+    };
 
-    let mut expr = DAG::new(false);
     expr.set(0, Value::Const(0));
 
+    let changed = body_expr
+        .terminals()
+        .filter(|(k, v)| body_expr[*v] != Value::Tape(*k))
+        .map(|(k, _)| k)
+        .collect::<HashSet<_>>();
+
     for (k, v) in body_expr.terminals() {
-        if k == 0 {
-            continue;
-        } else if body_expr[v] == Value::Tape(k) {
+        if !changed.contains(&k) || k == 0 {
             continue;
-        } else if let Some(a) = body_expr.as_add_const(k) {
-            let tapeval = expr.add_node(Value::Tape(k));
-            let lhs = expr.add_node(Value::Tape(0));
-            let rhs = expr.add_node(Value::Const(a));
-            let addend = expr.add_node(Value::Multiply(lhs, rhs));
-            expr.set(k, Value::Add(tapeval, addend));
-        } else if let Value::Const(a) = body_expr[v] {
-            expr.set(k, Value::Const(a));
+        } else if let Some(terms) = increment(body_expr, v, k, &changed) {
+            let mut addend = None;
+            for term in terms {
+                let term = expr.import(body_expr, term);
+                addend = Some(match addend {
+                    Some(addend) => expr.add_node(Value::Add(addend, term)),
+                    None => term,
+                });
+            }
+            if let Some(addend) = addend {
+                let tapeval = expr.add_node(Value::Tape(k));
+                let addend = expr.add_node(Value::Multiply(count, addend));
+                expr.set(k, Value::Add(tapeval, addend));
+            }
+        } else if body_expr.dependencies(v).is_disjoint(&changed) {
+            if counter.is_none() && !if_loops {
+                return Err("if-loops pass is disabled".to_string());
+            }
+            let node = expr.import(body_expr, v);
+            if counter.is_some() {
+                // The counter is known not to be zero, so the loop runs
+                // at least once
+                expr.set(k, expr[node]);
+            } else {
+                let cond = expr.add_node(Value::Tape(0));
+                let else_ = expr.add_node(Value::Tape(k));
+                expr.set(k, Value::Select(cond, node, else_));
+            }
         } else {
-            return None;
+            return Err(format!(
+                "cell at offset {} depends on cells the loop changes",
+                k
+            ));
         }
     }
 
-    Some(expr)
+    Ok(LoopForm::Flat(expr))
 }
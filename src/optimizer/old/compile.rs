@@ -1,12 +1,14 @@
+use std::mem;
+use std::slice;
+
 use super::token::Token;
 use crate::lir::{self, LIRBuilder, LIR};
+use crate::optimizer::output::OutputBuffer;
 
 #[derive(Default)]
 struct CompileState {
     lir: LIRBuilder,
-    loopnum: i32,
-    ifnum: i32,
-    outbuffsize: usize,
+    outbuf: OutputBuffer,
     regnum: u32,
 }
 
@@ -22,8 +24,28 @@ impl CompileState {
 fn compile_iter(state: &mut CompileState, tokens: &[Token]) {
     use lir::prelude::*;
 
-    let mut outbuffpos = 0;
-    for token in tokens {
+    // Loops and ifs being compiled, innermost last: each token, the rest
+    // of the tokens around it, and the LIR of those so far
+    let mut blocks: Vec<(&Token, slice::Iter<Token>, LIRBuilder)> = Vec::new();
+
+    let mut rest = tokens.iter();
+    loop {
+        let token = match rest.next() {
+            Some(token) => token,
+            None => match blocks.pop() {
+                Some((token, outer_rest, outer)) => {
+                    rest = outer_rest;
+                    let body = mem::replace(&mut state.lir, outer).build();
+                    match *token {
+                        Token::If(offset, _) => state.lir.if_(Tape(offset), body),
+                        _ => state.lir.loop_(Tape(0), body),
+                    };
+                    continue;
+                }
+                None => return,
+            },
+        };
+
         match *token {
             Token::Add(offset, value) => {
                 state.lir.add(Tape(offset), Tape(offset), Immediate(value));
@@ -39,37 +61,12 @@ fn compile_iter(state: &mut CompileState, tokens: &[Token]) {
             Token::Move(offset) => {
                 state.lir.shift(offset);
             }
-            Token::Loop(ref content) => {
-                state.loopnum += 1;
-                let startlabel = format!("loop{}", state.loopnum);
-                let endlabel = format!("endloop{}", state.loopnum);
-                state.lir.jp(endlabel.clone());
-                state.lir.label(startlabel.clone());
-
-                compile_iter(state, &content);
-
-                state.lir.label(endlabel.clone());
-                state.lir.jnz(Tape(0), startlabel.clone());
-            }
-            Token::If(offset, ref content) => {
-                state.ifnum += 1;
-                let endlabel = format!("endif{}", state.ifnum);
-                state.lir.jz(Tape(offset), endlabel.clone());
-
-                compile_iter(state, &content);
-
-                state.lir.label(endlabel.clone());
+            Token::Loop(_, ref content) | Token::If(_, ref content) => {
+                let outer = mem::take(&mut state.lir);
+                blocks.push((token, mem::replace(&mut rest, content.iter()), outer));
             }
             Token::Scan(offset) => {
-                // Slighly more optimal than normal loop and move
-                state.loopnum += 1;
-                let startlabel = format!("loop{}", state.loopnum);
-                let endlabel = format!("endloop{}", state.loopnum);
-                state.lir.jp(endlabel.clone());
-                state.lir.label(startlabel.clone());
-                state.lir.shift(offset);
-                state.lir.label(endlabel.clone());
-                state.lir.jnz(Tape(0), startlabel.clone());
+                state.lir.scan(offset);
             }
             // XXX
             Token::Input => {
@@ -79,21 +76,16 @@ fn compile_iter(state: &mut CompileState, tokens: &[Token]) {
             Token::LoadOut(offset, addend) => {
                 let reg = state.reg();
                 state.lir.add(Reg(reg), Tape(offset), Immediate(addend));
-                state.lir.mov(Buf("strbuf".into(), outbuffpos), Reg(reg));
-                outbuffpos += 1;
+                state.outbuf.push(Reg(reg).into());
             }
             Token::LoadOutSet(value) => {
-                state
-                    .lir
-                    .mov(Buf("strbuf".into(), outbuffpos), Immediate(value));
-                outbuffpos += 1;
+                state.outbuf.push(Immediate(value));
             }
             Token::Output => {
-                state.lir.output("strbuf", 0, outbuffpos);
-                if state.outbuffsize < outbuffpos + 1 {
-                    state.outbuffsize = outbuffpos + 1;
-                }
-                outbuffpos = 0;
+                state.outbuf.flush(&mut state.lir);
+            }
+            Token::Debug => {
+                state.lir.debug_dump(0);
             }
         }
     }
@@ -102,7 +94,7 @@ fn compile_iter(state: &mut CompileState, tokens: &[Token]) {
 pub fn compile(tokens: &[Token]) -> Vec<LIR> {
     let mut state = CompileState::default();
     compile_iter(&mut state, tokens);
-    state.lir.declare_bss_buf("strbuf", state.outbuffsize);
+    state.outbuf.declare(&mut state.lir);
     state.lir.declare_bss_buf("inputbuf", 1);
     state.lir.build()
 }
@@ -1,28 +1,82 @@
-use super::Optimizer;
+use super::{Optimizer, Pass, PassConfig};
+use crate::lir::CellType;
 use crate::{AST, LIR};
 use std::io::Write;
 
 mod compile;
 mod optimize;
 mod optimize_state;
-mod token;
+pub(crate) mod token;
 
 pub struct OldOptimizer;
 
+pub(crate) const PASSES: &[Pass] = &[
+    Pass {
+        name: "combine",
+        level: 1,
+        description: "combine adds, sets and moves, and delay output",
+    },
+    Pass {
+        name: "const-output",
+        level: 1,
+        description: "write constants rather than cells with known values",
+    },
+    Pass {
+        name: "scan",
+        level: 1,
+        description: "turn loops that only move the cursor into scans",
+    },
+    Pass {
+        name: "counter-loops",
+        level: 1,
+        description: "turn loops with a fixed step into multiplies",
+    },
+    Pass {
+        name: "if-loops",
+        level: 2,
+        description: "turn loops that clear their cell into ifs",
+    },
+];
+
+pub(crate) fn optimize_tokens(
+    ast: &[AST],
+    passes: &PassConfig,
+    cell: CellType,
+) -> Vec<token::Token> {
+    let mut tokens = token::ast_to_tokens(ast);
+    // The other passes are all part of combining tokens
+    if passes.enabled("combine") {
+        tokens = optimize::optimize(tokens, cell, passes);
+    }
+    passes.dump(
+        &[
+            "combine",
+            "const-output",
+            "scan",
+            "counter-loops",
+            "if-loops",
+        ],
+        &tokens,
+    );
+    tokens
+}
+
 impl Optimizer for OldOptimizer {
-    fn optimize(&self, ast: &[AST], level: u32) -> Vec<LIR> {
-        let mut tokens = token::ast_to_tokens(ast);
-        if level > 0 {
-            tokens = optimize::optimize(&tokens);
-        }
-        compile::compile(&tokens)
+    fn passes(&self) -> &'static [Pass] {
+        PASSES
+    }
+
+    fn optimize(&self, ast: &[AST], passes: &PassConfig, cell: CellType) -> Vec<LIR> {
+        compile::compile(&optimize_tokens(ast, passes, cell))
     }
 
-    fn dumpir(&self, ast: &[AST], level: u32, file: &mut dyn Write) -> std::io::Result<()> {
-        let mut tokens = token::ast_to_tokens(ast);
-        if level > 0 {
-            tokens = optimize::optimize(&tokens);
-        }
-        writeln!(file, "{:#?}", tokens)
+    fn dumpir(
+        &self,
+        ast: &[AST],
+        passes: &PassConfig,
+        cell: CellType,
+        file: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        writeln!(file, "{:#?}", optimize_tokens(ast, passes, cell))
     }
 }
@@ -1,20 +1,123 @@
+use std::collections::HashMap;
 use std::iter::FromIterator;
+use std::mem;
+use std::rc::Rc;
 
 use super::optimize_state::OptimizeState;
 use super::token::Token;
 use super::token::Token::*;
+use crate::lir::CellType;
+use crate::optimizer::counter::{trip_count, trip_multiplier};
+use crate::optimizer::{LoopRemark, PassConfig};
 
-fn _optimize(tokens: &[Token]) -> OptimizeState {
-    let mut do_output = false;
-    let mut state = OptimizeState::default();
+/// Most times a body is optimized before giving up on a fixpoint
+const MAX_ROUNDS: usize = 32;
+
+/// A loop body at its fixpoint
+struct Body {
+    state: OptimizeState,
+    /// The body as emitted
+    tokens: Rc<Vec<Token>>,
+    /// Whether the body, with any loops in it, ends where it started
+    balanced: bool,
+}
+
+/// Optimized bodies of loops that were emitted as loops, by loop id. A
+/// body doesn't depend on what is around its loop, so once it is at a
+/// fixpoint it never has to be optimized again.
+type Bodies = HashMap<usize, Body>;
+
+/// What the optimizer works with besides the tokens
+struct Context<'a> {
+    cell: CellType,
+    passes: &'a PassConfig,
+    bodies: Bodies,
+}
+
+/// What a `Frame` optimizes
+#[derive(Clone, Copy)]
+enum Contents {
+    Program,
+    /// The body of loop *id*, with the value of its cell before the loop
+    /// if that is known
+    Loop(usize, Option<i64>),
+    /// The contents of an `If` at *offset*
+    If(i32),
+}
+
+/// A body being optimized. The program and loop bodies are optimized
+/// repeatedly, until that no longer changes them or `MAX_ROUNDS` is
+/// reached. Each round is a valid optimization, so stopping early only
+/// loses some of it.
+struct Frame {
+    contents: Contents,
+    /// Tokens this round optimizes
+    tokens: Rc<Vec<Token>>,
+    /// Index in *tokens* of the next token to optimize
+    next: usize,
+    state: OptimizeState,
+    do_output: bool,
+    round: usize,
+    /// Length of the last round's tokens, before its adds, sets and shift
+    /// were applied to give *tokens*
+    last_len: usize,
+}
+
+impl Frame {
+    fn new(contents: Contents, tokens: Rc<Vec<Token>>) -> Self {
+        Self {
+            contents,
+            tokens,
+            next: 0,
+            state: OptimizeState::default(),
+            do_output: false,
+            round: 1,
+            last_len: 0,
+        }
+    }
+
+    /// Whether the round just finished didn't change the body
+    fn at_fixpoint(&self) -> bool {
+        self.round > 1 && self.state.tokens[..] == self.tokens[..self.last_len]
+    }
+
+    /// Starts another round, over the body as the last one would emit it
+    fn next_round(&mut self) {
+        let mut state = mem::take(&mut self.state);
+        self.last_len = state.tokens.len();
+        state.apply_adds_sets();
+        state.apply_shift();
+        self.tokens = Rc::new(state.tokens);
+        self.next = 0;
+        self.round += 1;
+    }
+
+    /// Optimizes the next token. If its contents have to be optimized
+    /// first, returns a frame for them instead, and `resume` finishes the
+    /// token once they are.
+    fn step(&mut self, cx: &mut Context) -> Option<Frame> {
+        let Frame {
+            tokens,
+            next,
+            state,
+            do_output,
+            ..
+        } = self;
+        let token = &tokens[*next];
+        *next += 1;
+
+        // Value of a loop's cell, if known, before sets are applied
+        let counter = match *token {
+            Loop(..) => state.sets.get(&state.shift).cloned(),
+            _ => None,
+        };
 
-    for token in tokens {
         match *token {
             Set(..) | Add(..) | Move(_) | LoadOut(..) | LoadOutSet(_) | Output | MulCopy(..) => {}
             _ => {
-                if do_output {
+                if *do_output {
                     state.tokens.push(Output);
-                    do_output = false;
+                    *do_output = false;
                 }
 
                 state.apply_adds_sets();
@@ -22,7 +125,7 @@ fn _optimize(tokens: &[Token]) -> OptimizeState {
         }
 
         match *token {
-            Loop(_) | Input | Scan(_) => {
+            Loop(..) | Input | Scan(_) | Debug => {
                 state.apply_shift();
             }
             _ => {}
@@ -41,7 +144,7 @@ fn _optimize(tokens: &[Token]) -> OptimizeState {
                 src += state.shift;
                 dest += state.shift;
                 if let Some(value) = state.sets.get(&src).cloned() {
-                    state.add(dest, value * mul);
+                    state.add(dest, value.wrapping_mul(mul));
                 } else {
                     if state.sets.contains_key(&dest)
                         || state.adds.contains_key(&src)
@@ -53,69 +156,253 @@ fn _optimize(tokens: &[Token]) -> OptimizeState {
                 }
             }
             If(offset, ref contents) => {
-                let mut newcontents = Vec::new();
-                for i in contents {
-                    newcontents.push(match *i {
-                        Set(offset, value) => Set(offset + state.shift, value),
-                        MulCopy(src, dest, mul) => {
-                            MulCopy(src + state.shift, dest + state.shift, mul)
-                        }
-                        _ => unreachable!(),
-                    });
-                }
-                state.tokens.push(If(offset + state.shift, newcontents));
+                return Some(Frame::new(Contents::If(offset), contents.clone()));
             }
             Move(offset) => state.shift += offset,
-            Output => do_output = true,
+            Output => *do_output = true,
             LoadOut(mut offset, add) => {
                 offset += state.shift;
-                state
-                    .tokens
-                    .push(if let Some(set) = state.sets.get_mut(&offset) {
-                        LoadOutSet(*set + add)
-                    } else {
-                        LoadOut(offset, state.adds.get(&offset).unwrap_or(&0) + add)
-                    });
-            }
-            Loop(ref contents) => _optimize_loop(contents, &mut state),
+                match state.sets.get(&offset) {
+                    Some(set) if cx.passes.enabled("const-output") => {
+                        state.tokens.push(LoadOutSet(set.wrapping_add(add)));
+                    }
+                    _ => {
+                        if state.sets.contains_key(&offset) {
+                            // The cell has to be set before it is read
+                            state.apply_adds_sets();
+                        }
+                        let add = state.adds.get(&offset).unwrap_or(&0).wrapping_add(add);
+                        state.tokens.push(LoadOut(offset, add));
+                    }
+                }
+            }
+            Loop(id, ref contents) => match cx.bodies.remove(&id) {
+                Some(body) => _optimize_loop(id, counter, body.state, Some(body.tokens), state, cx),
+                None => return Some(Frame::new(Contents::Loop(id, counter), contents.clone())),
+            },
             LoadOutSet(value) => state.tokens.push(LoadOutSet(value)),
             Input => state.tokens.push(Input),
+            Debug => state.tokens.push(Debug),
             Scan(offset) => state.tokens.push(Scan(offset + state.shift)),
         }
+        None
     }
 
-    if do_output {
-        state.tokens.push(Output);
+    /// Finishes the token before `next`, whose *contents* were optimized
+    /// to *inner*
+    fn resume(&mut self, contents: Contents, mut inner: OptimizeState, cx: &mut Context) {
+        let state = &mut self.state;
+        match contents {
+            Contents::Program => unreachable!("the program isn't inside a token"),
+            Contents::Loop(id, counter) => _optimize_loop(id, counter, inner, None, state, cx),
+            Contents::If(offset) => {
+                inner.apply_adds_sets();
+                inner.apply_shift();
+                if !inner.tokens.is_empty() {
+                    // Contents are relative to the cursor, so if they move
+                    // it, it has to be moved first
+                    let contents = match offset_tokens(&inner.tokens, state.shift) {
+                        Some(contents) => contents,
+                        None => {
+                            state.apply_shift();
+                            inner.tokens
+                        }
+                    };
+                    state
+                        .tokens
+                        .push(If(offset + state.shift, Rc::new(contents)));
+                }
+            }
+        }
     }
+}
+
+/// *tokens* with *shift* added to every offset, or `None` if they depend
+/// on where the cursor is
+fn offset_tokens(tokens: &[Token], shift: i32) -> Option<Vec<Token>> {
+    // Ifs being offset, innermost last: each one's offset, the rest of
+    // the tokens around it, and those offset so far
+    let mut ifs = Vec::new();
 
-    state
+    let mut rest = tokens.iter();
+    let mut offset_tokens = Vec::new();
+    loop {
+        let token = match rest.next() {
+            Some(token) => token,
+            None => match ifs.pop() {
+                Some((offset, outer_rest, outer)) => {
+                    rest = outer_rest;
+                    let contents = mem::replace(&mut offset_tokens, outer);
+                    offset_tokens.push(If(offset + shift, Rc::new(contents)));
+                    continue;
+                }
+                None => return Some(offset_tokens),
+            },
+        };
+        offset_tokens.push(match *token {
+            Set(offset, value) => Set(offset + shift, value),
+            Add(offset, value) => Add(offset + shift, value),
+            MulCopy(src, dest, mul) => MulCopy(src + shift, dest + shift, mul),
+            LoadOut(offset, add) => LoadOut(offset + shift, add),
+            LoadOutSet(value) => LoadOutSet(value),
+            Output => Output,
+            If(offset, ref contents) => {
+                let outer = mem::take(&mut offset_tokens);
+                ifs.push((offset, mem::replace(&mut rest, contents.iter()), outer));
+                continue;
+            }
+            Move(_) | Loop(..) | Scan(_) | Input | Debug => return None,
+        });
+    }
 }
 
-fn _optimize_loop(tokens: &[Token], outer: &mut OptimizeState) {
-    let mut inner = _optimize(tokens);
+/// How far *tokens* move the cursor, or `None` if that isn't fixed. Loops
+/// in *tokens* must be in *bodies*, and ifs always end where they started.
+fn net_shift(tokens: &[Token], bodies: &Bodies) -> Option<i32> {
+    let mut shift = 0;
+    for token in tokens {
+        match *token {
+            Move(offset) => shift += offset,
+            Loop(id, _) if !bodies.get(&id).is_some_and(|body| body.balanced) => return None,
+            Scan(_) => return None,
+            _ => {}
+        }
+    }
+    Some(shift)
+}
 
-    if inner.shift != 0 && inner.sets.is_empty() && inner.adds.is_empty() && inner.tokens.is_empty()
-    {
-        outer.tokens.push(Scan(inner.shift));
-    } else if inner.shift == 0
+/// Why a loop with body *inner* isn't a counter loop
+fn not_counter_reason(inner: &OptimizeState, passes: &PassConfig) -> String {
+    let io = |token: &Token| matches!(token, Input | Output | LoadOut(..) | LoadOutSet(_) | Debug);
+    if !passes.enabled("counter-loops") {
+        "counter-loops pass is disabled".to_string()
+    } else if inner.tokens.iter().any(io) {
+        "body contains I/O".to_string()
+    } else if !inner.tokens.is_empty() {
+        "body contains a loop".to_string()
+    } else if inner.shift != 0 {
+        format!("body moves the cursor by {}", inner.shift)
+    } else if inner.sets.is_empty() && inner.adds.is_empty() {
+        "body is empty".to_string()
+    } else {
+        "counter isn't changed by a constant".to_string()
+    }
+}
+
+fn _optimize_loop(
+    id: usize,
+    counter: Option<i64>,
+    mut inner: OptimizeState,
+    emitted: Option<Rc<Vec<Token>>>,
+    outer: &mut OptimizeState,
+    cx: &mut Context,
+) {
+    let (cell, passes, bodies) = (cx.cell, cx.passes, &mut cx.bodies);
+    let balanced = net_shift(&inner.tokens, bodies).map(|shift| shift + inner.shift) == Some(0);
+    // Emits the loop, keeping its body for later rounds
+    let mut keep_loop = |inner: OptimizeState,
+                         emitted: Rc<Vec<Token>>,
+                         balanced: bool,
+                         outer: &mut OptimizeState| {
+        outer.tokens.push(Loop(id, emitted.clone()));
+        bodies.insert(
+            id,
+            Body {
+                state: inner,
+                tokens: emitted,
+                balanced,
+            },
+        );
+    };
+
+    if passes.enabled("scan")
+        && inner.shift != 0
+        && inner.sets.is_empty()
+        && inner.adds.is_empty()
         && inner.tokens.is_empty()
-        && inner.adds.contains_key(&0)
-        && inner.adds.len() == 1
     {
-        if !inner.sets.is_empty() {
-            let mut iftokens = Vec::new();
-            for (offset, value) in &inner.sets {
-                iftokens.push(Set(*offset, *value));
+        outer.tokens.push(Scan(inner.shift));
+        passes.remark(id, LoopRemark::Scan);
+        return;
+    }
+
+    // A loop that clears its cell and ends where it started runs at most
+    // once. The sets are relative to the cursor before `inner.shift`.
+    let clears = inner.sets.get(&inner.shift).map(|value| cell.wrap(*value)) == Some(0);
+    if passes.enabled("if-loops") && clears && balanced {
+        inner.apply_adds_sets();
+        inner.apply_shift();
+        let remark = match counter.map(|counter| cell.wrap(counter)) {
+            Some(0) => LoopRemark::Removed,
+            Some(_) => {
+                outer.tokens.extend(inner.tokens);
+                LoopRemark::Expr
             }
-            iftokens.push(Set(0, 0));
-            outer.tokens.push(If(0, iftokens));
-        } else {
-            outer.set(0, 0);
+            None => {
+                outer.tokens.push(If(0, Rc::new(inner.tokens)));
+                LoopRemark::If
+            }
+        };
+        passes.remark(id, remark);
+        return;
+    }
+
+    // A counter loop, which only sets cells and adds constants to them
+    let step = match inner.adds.get(&0) {
+        Some(step)
+            if passes.enabled("counter-loops") && inner.shift == 0 && inner.tokens.is_empty() =>
+        {
+            *step
+        }
+        _ => {
+            passes.remark(id, LoopRemark::Loop(not_counter_reason(&inner, passes)));
+            let emitted = emitted.unwrap_or_else(|| Rc::new(inner.flushed()));
+            keep_loop(inner, emitted, balanced, outer);
+            return;
         }
-    } else if inner.shift == 0 && inner.tokens.is_empty() && inner.adds.get(&0) == Some(&-1) {
+    };
+
+    if let Some(counter) = counter {
+        // Starting value is known, so the loop can be run at compile time
+        match trip_count(counter, step, cell) {
+            Some(0) => passes.remark(id, LoopRemark::Removed),
+            Some(count) => {
+                passes.remark(id, LoopRemark::Expr);
+                for (offset, value) in &inner.sets {
+                    outer.set(*offset, *value);
+                }
+                for (offset, value) in &inner.adds {
+                    if *offset != 0 {
+                        outer.add(*offset, cell.wrap(value.wrapping_mul(count)));
+                    }
+                }
+            }
+            None => {
+                // Never terminates, and nothing in the body is observable
+                keep_loop(inner, Rc::default(), true, outer);
+                passes.remark(id, LoopRemark::Infinite);
+                return;
+            }
+        }
+        outer.set(0, 0);
+    } else if let Some(multiplier) = trip_multiplier(step, cell) {
+        // A plain clear, like `[-]`, only sets the counter to 0
+        let muls = inner.adds.keys().any(|offset| *offset != 0);
+        let remark = if muls {
+            LoopRemark::MulCopy
+        } else if !inner.sets.is_empty() {
+            LoopRemark::If
+        } else {
+            LoopRemark::Expr
+        };
+        passes.remark(id, remark);
         let contents = inner.adds.iter().filter_map(|(offset, value)| {
             if *offset != 0 {
-                Some(MulCopy(0, *offset, *value))
+                Some(MulCopy(
+                    0,
+                    *offset,
+                    cell.wrap(value.wrapping_mul(multiplier)),
+                ))
             } else {
                 None
             }
@@ -129,28 +416,68 @@ fn _optimize_loop(tokens: &[Token], outer: &mut OptimizeState) {
                     .map(|(offset, value)| Set(*offset, *value))
                     .chain(contents),
             );
-            outer.tokens.push(If(0, iftokens));
+            outer.tokens.push(If(0, Rc::new(iftokens)));
         } else {
             outer.tokens.extend(contents);
         }
 
         outer.set(0, 0);
     } else {
-        inner.apply_adds_sets();
-        inner.apply_shift();
-
-        outer.tokens.push(Loop(inner.tokens));
+        // With an even step, the loop may not terminate
+        passes.remark(id, LoopRemark::Loop(format!("counter step is {}", step)));
+        let emitted = emitted.unwrap_or_else(|| Rc::new(inner.flushed()));
+        keep_loop(inner, emitted, balanced, outer);
     }
 }
 
 /// Returns an optimized version of the intermediate representation
-pub fn optimize(tokens: &[Token]) -> Vec<Token> {
-    // Ignore sets/adds/shifts at end of file
-    let mut oldtokens = _optimize(tokens).tokens;
-    let mut newtokens = _optimize(&oldtokens).tokens;
-    while newtokens != oldtokens {
-        oldtokens = newtokens;
-        newtokens = _optimize(&oldtokens).tokens;
+///
+/// Loop bodies are optimized bottom-up, each to its own fixpoint, before
+/// the loop itself; later rounds over the enclosing body reuse them. Bodies
+/// are kept on an explicit stack, rather than optimized recursively, so
+/// deeply nested programs can't overflow the stack.
+pub fn optimize(tokens: Vec<Token>, cell: CellType, passes: &PassConfig) -> Vec<Token> {
+    let mut cx = Context {
+        cell,
+        passes,
+        bodies: Bodies::new(),
+    };
+    // Frames waiting on the one being optimized, innermost last
+    let mut stack = Vec::new();
+
+    let mut frame = Frame::new(Contents::Program, Rc::new(tokens));
+    loop {
+        if frame.next < frame.tokens.len() {
+            if let Some(inner) = frame.step(&mut cx) {
+                stack.push(mem::replace(&mut frame, inner));
+            }
+            continue;
+        }
+
+        if frame.do_output {
+            frame.state.tokens.push(Output);
+            frame.do_output = false;
+        }
+        // If contents are only optimized once
+        if !matches!(frame.contents, Contents::If(_)) && !frame.at_fixpoint() {
+            if frame.round < MAX_ROUNDS {
+                frame.next_round();
+                continue;
+            }
+            cx.passes.warn(format!(
+                "optimizer stopped after {} rounds without reaching a fixpoint",
+                MAX_ROUNDS
+            ));
+        }
+
+        let inner = mem::take(&mut frame.state);
+        match stack.pop() {
+            Some(outer) => {
+                let contents = mem::replace(&mut frame, outer).contents;
+                frame.resume(contents, inner, &mut cx);
+            }
+            // Ignore sets/adds/shifts at end of file
+            None => return inner.tokens,
+        }
     }
-    newtokens
 }
@@ -1,13 +1,13 @@
 use super::token::Token;
 use std::collections::BTreeMap;
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct OptimizeState {
     pub tokens: Vec<Token>,
     // With HashMap, the order sometimes switches
-    // in recursion, and the optimizer never exits.
-    pub adds: BTreeMap<i32, i32>,
-    pub sets: BTreeMap<i32, i32>,
+    // in recursion, and bodies never reach a fixpoint.
+    pub adds: BTreeMap<i32, i64>,
+    pub sets: BTreeMap<i32, i64>,
     pub shift: i32,
 }
 
@@ -30,11 +30,19 @@ impl OptimizeState {
         self.adds.clear();
     }
 
-    pub fn add(&mut self, offset: i32, mut value: i32) {
+    /// The tokens, followed by the adds, sets and shift applied
+    pub fn flushed(&self) -> Vec<Token> {
+        let mut state = self.clone();
+        state.apply_adds_sets();
+        state.apply_shift();
+        state.tokens
+    }
+
+    pub fn add(&mut self, offset: i32, mut value: i64) {
         if let Some(set) = self.sets.get_mut(&offset) {
-            *set += value;
+            *set = set.wrapping_add(value);
         } else {
-            value += self.adds.get(&offset).unwrap_or(&0);
+            value = value.wrapping_add(*self.adds.get(&offset).unwrap_or(&0));
             if value != 0 {
                 self.adds.insert(offset, value);
             } else {
@@ -43,7 +51,7 @@ impl OptimizeState {
         }
     }
 
-    pub fn set(&mut self, offset: i32, value: i32) {
+    pub fn set(&mut self, offset: i32, value: i64) {
         // Add before Set does nothing; remove it
         self.adds.remove(&offset);
         self.sets.insert(offset, value);
@@ -1,4 +1,6 @@
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
 use crate::AST;
 
@@ -9,26 +11,54 @@ pub enum Token {
     Output,
     /// `Input` Reads one byte from stdin to the current cell
     Input,
-    /// `Loop(content)` Runs *content* in loop while current cell is not zero
-    Loop(Vec<Token>),
+    /// `Loop(id, content)` Runs *content* in loop while current cell is not
+    /// zero. *id* is the source loop it came from. *content* is shared, so
+    /// the optimizer can keep emitting an optimized body without copying or
+    /// comparing it again.
+    Loop(usize, Rc<Vec<Token>>),
     /// `Move(offset)` Moves data pointer by *offset* cells
     Move(i32),
     /// `Add(offset, value)` Adds *value* to cell at *offset*
-    Add(i32, i32),
+    Add(i32, i64),
     /// `Set(offset, value)` Sets cell at *offset* to *value*
-    Set(i32, i32),
+    Set(i32, i64),
     /// `MulCopy(src, dest, mul)` Adds product of *mul* and the value at offset
     /// *src* to the cell at offset *dest*
-    MulCopy(i32, i32, i32),
+    MulCopy(i32, i32, i64),
     /// `Scan(offset)` Equivalent to `Loop(Move(offset))`
     Scan(i32),
     /// `LoadOut(offset, add)` Appends the value of the cell at *offset* plus
     /// *add* to the output buffer
-    LoadOut(i32, i32),
+    LoadOut(i32, i64),
     /// `LoadOutSet(value)` Appends the constant value *value* to the output buffer
-    LoadOutSet(i32),
-    /// `LoadOutSet(offset, content)` Runs *content* if the cell at *offset* is not zero
-    If(i32, Vec<Token>),
+    LoadOutSet(i64),
+    /// `If(offset, content)` Runs *content* once if the cell at *offset* is
+    /// not zero. Offsets in *content* are relative to the cursor, not
+    /// *offset*, and it must leave the cursor where it started. *content* is
+    /// shared like a `Loop`'s.
+    If(i32, Rc<Vec<Token>>),
+    /// `Debug` Writes the cells around the cursor to stderr
+    Debug,
+}
+
+impl Drop for Token {
+    /// Drops nested contents from a stack, rather than recursively, so
+    /// deeply nested programs can't overflow the stack. Contents that are
+    /// still shared are left to their last reference.
+    fn drop(&mut self) {
+        if let Token::Loop(_, contents) | Token::If(_, contents) = self {
+            if let Some(tokens) = Rc::get_mut(contents) {
+                let mut stack = mem::take(tokens);
+                while let Some(mut token) = stack.pop() {
+                    if let Token::Loop(_, contents) | Token::If(_, contents) = &mut token {
+                        if let Some(tokens) = Rc::get_mut(contents) {
+                            stack.append(tokens);
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl fmt::Debug for Token {
@@ -36,6 +66,7 @@ impl fmt::Debug for Token {
         match *self {
             Token::Output => write!(f, "Output"),
             Token::Input => write!(f, "Input"),
+            Token::Debug => write!(f, "Debug"),
             Token::Move(offset) => write!(f, "Move(offset={})", offset),
             Token::Add(offset, value) => write!(f, "Add(offset={}, value={})", offset, value),
             Token::Set(offset, value) => write!(f, "Set(offset={}, value={})", offset, value),
@@ -45,7 +76,7 @@ impl fmt::Debug for Token {
             Token::Scan(offset) => write!(f, "Scan(offset={})", offset),
             Token::LoadOut(offset, add) => write!(f, "LoadOut(offset={}, add={})", offset, add),
             Token::LoadOutSet(value) => write!(f, "LoadOutSet(value={})", value),
-            Token::Loop(ref content) => {
+            Token::Loop(_, ref content) => {
                 if f.alternate() {
                     write!(f, "Loop(content={:#?})", content)
                 } else {
@@ -63,19 +94,41 @@ impl fmt::Debug for Token {
     }
 }
 
+/// Converts *ast*, numbering loops in pre-order
 pub fn ast_to_tokens(ast: &[AST]) -> Vec<Token> {
+    let mut next_loop = 0;
+    // Loops being converted, innermost last: the rest of the body around
+    // each, its id, and that body's tokens so far
+    let mut loops = Vec::new();
+
+    let mut rest = ast.iter();
     let mut tokens = Vec::new();
-    for i in ast {
-        match i {
-            AST::Output => {
+    loop {
+        match rest.next() {
+            Some(AST::Output) => {
                 tokens.push(Token::LoadOut(0, 0));
                 tokens.push(Token::Output);
             }
-            AST::Input => tokens.push(Token::Input),
-            AST::Loop(inner) => tokens.push(Token::Loop(ast_to_tokens(inner))),
-            AST::Shift(offset) => tokens.push(Token::Move(*offset)),
-            AST::Add(add) => tokens.push(Token::Add(0, *add)),
+            Some(AST::Input) => tokens.push(Token::Input),
+            Some(AST::Debug) => tokens.push(Token::Debug),
+            Some(AST::Loop(inner)) => {
+                loops.push((
+                    mem::replace(&mut rest, inner.iter()),
+                    next_loop,
+                    mem::take(&mut tokens),
+                ));
+                next_loop += 1;
+            }
+            Some(AST::Shift(offset)) => tokens.push(Token::Move(*offset)),
+            Some(AST::Add(add)) => tokens.push(Token::Add(0, i64::from(*add))),
+            None => match loops.pop() {
+                Some((outer_rest, id, outer)) => {
+                    rest = outer_rest;
+                    let body = mem::replace(&mut tokens, outer);
+                    tokens.push(Token::Loop(id, Rc::new(body)));
+                }
+                None => return tokens,
+            },
         }
     }
-    tokens
 }
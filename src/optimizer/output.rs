@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use crate::lir::{CowStr, LIRBuilder, RVal};
+
+/// Collects the bytes written by consecutive Brainfuck `.` commands, so they
+/// can be emitted with a single `Output`.
+///
+/// If every byte is known at compile time, the bytes are placed in a
+/// read-only buffer instead of being stored into `strbuf` one by one.
+#[derive(Default)]
+pub struct OutputBuffer {
+    pending: Vec<RVal>,
+    strbuf_size: usize,
+    consts: HashMap<Vec<u8>, CowStr>,
+}
+
+impl OutputBuffer {
+    /// Appends a byte to the pending output. *value* must not be a tape
+    /// cell that could change before the next `flush`.
+    pub fn push(&mut self, value: RVal) {
+        self.pending.push(value);
+    }
+
+    /// Emits LIR writing all pending output
+    pub fn flush(&mut self, lir: &mut LIRBuilder) {
+        use crate::lir::prelude::*;
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let consts = self
+            .pending
+            .iter()
+            .map(|value| match value {
+                RVal::Immediate(value) => Some(*value as u8),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>();
+
+        if let Some(data) = consts {
+            let num = self.consts.len();
+            let name = self
+                .consts
+                .entry(data)
+                .or_insert_with(|| format!("const{}", num).into());
+            lir.output(name.clone(), 0, self.pending.len());
+        } else {
+            for (i, value) in self.pending.iter().enumerate() {
+                lir.mov(Buf("strbuf".into(), i), value.clone());
+            }
+            lir.output("strbuf", 0, self.pending.len());
+            self.strbuf_size = self.strbuf_size.max(self.pending.len() + 1);
+        }
+
+        self.pending.clear();
+    }
+
+    /// Emits declarations for `strbuf` and all read-only buffers. Must be
+    /// called after the last `flush`.
+    pub fn declare(&self, lir: &mut LIRBuilder) {
+        lir.declare_bss_buf("strbuf", self.strbuf_size);
+        let mut consts = self.consts.iter().collect::<Vec<_>>();
+        consts.sort_by(|a, b| a.1.cmp(b.1));
+        for (data, name) in consts {
+            lir.declare_rodata_buf(name.clone(), data.clone());
+        }
+    }
+}
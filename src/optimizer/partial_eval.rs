@@ -0,0 +1,133 @@
+//! Compile time evaluation of the start of a program.
+//!
+//! The tape is known to be zeroed when a program starts, so everything up to
+//! the first `Input` or `Debug` can be run by the compiler. Only the output
+//! produced and the state of the tape need to be in the compiled program.
+
+use std::collections::HashMap;
+
+use crate::lir::CellType;
+use crate::{LIRBuilder, AST, LIR};
+
+/// Default number of steps `partial_eval` runs before giving up
+pub const DEFAULT_STEP_BUDGET: usize = 1_000_000;
+
+/// The result of evaluating the start of a program at compile time
+#[derive(Debug)]
+pub struct PartialEval {
+    /// Output written before evaluation stopped
+    pub output: Vec<u8>,
+    /// The remainder of the program. This starts by setting up the tape and
+    /// cursor as they were when evaluation stopped, so it can be compiled
+    /// like any other program.
+    pub rest: Vec<AST>,
+}
+
+impl PartialEval {
+    /// LIR writing the output produced at compile time, which must be run
+    /// before the code compiled from `rest`
+    pub fn output_lir(&self) -> Vec<LIR> {
+        let mut lir = LIRBuilder::new();
+        if !self.output.is_empty() {
+            lir.declare_rodata_buf("evaloutput", self.output.clone());
+            lir.output("evaloutput", 0, self.output.len());
+        }
+        lir.build()
+    }
+}
+
+/// Runs *ast* from an all zero tape of *cell* sized cells until it needs
+/// input or writes the tape to stderr, finishes, or has run *step_budget*
+/// steps.
+///
+/// Cells are kept within the range of `i32`, so with 64 bit cells
+/// evaluation also stops before a cell would leave it.
+pub fn partial_eval(ast: &[AST], cell: CellType, step_budget: usize) -> PartialEval {
+    let mut output = Vec::new();
+    let mut tape = HashMap::<i32, i32>::new();
+    let mut cursor = 0i32;
+    let mut steps = 0;
+
+    // Each frame is a body being run and the index of the next item in
+    // it. For enclosing frames, that is the loop currently being run.
+    let mut frames: Vec<(&[AST], usize)> = vec![(ast, 0)];
+
+    while let Some(&(body, pos)) = frames.last() {
+        if steps >= step_budget {
+            break;
+        }
+        steps += 1;
+
+        let value = tape.get(&cursor).cloned().unwrap_or(0);
+
+        let item = match body.get(pos) {
+            Some(item) => item,
+            None => {
+                // Reached the end of a loop body; check the condition again
+                if frames.len() > 1 && value != 0 {
+                    frames.last_mut().unwrap().1 = 0;
+                } else {
+                    frames.pop();
+                    if let Some(frame) = frames.last_mut() {
+                        frame.1 += 1;
+                    }
+                }
+                continue;
+            }
+        };
+
+        match item {
+            AST::Output => output.push(value as u8),
+            // The tape has to be written as the compiled program has it
+            AST::Input | AST::Debug => break,
+            AST::Loop(inner) => {
+                if value != 0 {
+                    frames.push((inner, 0));
+                    continue;
+                }
+            }
+            AST::Add(add) => match i32::try_from(cell.wrap(i64::from(value) + i64::from(*add))) {
+                Ok(value) => {
+                    tape.insert(cursor, value);
+                }
+                Err(_) => break,
+            },
+            AST::Shift(offset) => cursor += offset,
+        }
+        frames.last_mut().unwrap().1 += 1;
+    }
+
+    let mut rest = Vec::new();
+    for (body, pos) in frames.iter().rev() {
+        rest.extend_from_slice(&body[*pos..]);
+    }
+
+    if rest.is_empty() {
+        // Program finished; the final tape can't be observed
+        return PartialEval { output, rest };
+    }
+
+    let mut setup = Vec::new();
+    let mut cells = tape
+        .into_iter()
+        .filter(|(_, v)| *v != 0)
+        .collect::<Vec<_>>();
+    cells.sort();
+    let mut shift = 0;
+    for (offset, value) in cells {
+        if offset != shift {
+            setup.push(AST::Shift(offset - shift));
+            shift = offset;
+        }
+        setup.push(AST::Add(value));
+    }
+    if cursor != shift {
+        setup.push(AST::Shift(cursor - shift));
+    }
+    setup.extend(rest);
+
+    PartialEval {
+        output,
+        rest: setup,
+    }
+}
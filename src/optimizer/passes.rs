@@ -0,0 +1,144 @@
+//! Named optimization passes, which can be turned on and off
+//!
+//! Each optimizer lists the passes it has, in the order they run, and
+//! checks a `PassConfig` to decide which to apply. Some passes are whole
+//! stages of the optimizer; others are rewrites it applies during a stage,
+//! so dumping after one of those shows the IR once that stage is done.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use super::remarks::LoopRemark;
+
+/// An optimization pass
+pub struct Pass {
+    pub name: &'static str,
+    /// Lowest optimization level the pass is enabled at by default
+    pub level: u32,
+    pub description: &'static str,
+}
+
+/// Highest optimization level; every pass is enabled at this level
+pub const MAX_LEVEL: u32 = 3;
+
+/// Loops nested at most this deep are optimized with what is known before
+/// them, below `MAX_LEVEL`
+const KNOWN_DEPTH: usize = 3;
+
+/// `KNOWN_DEPTH` at `MAX_LEVEL`, which can take much longer on deeply
+/// nested programs, since those loops are optimized repeatedly
+const KNOWN_DEPTH_MAX_LEVEL: usize = 8;
+
+/// Which passes of an optimizer to run, and which to dump the IR after
+pub struct PassConfig {
+    passes: &'static [Pass],
+    enabled: HashSet<&'static str>,
+    dump_after: Option<&'static str>,
+    /// How deep nested loops can be and still be optimized with what is
+    /// known before them, by passes that use that
+    known_depth: usize,
+    /// Remarks by loop, if they are being collected
+    remarks: Option<RefCell<BTreeMap<usize, LoopRemark>>>,
+    /// Problems the optimizer ran into, for the caller to report
+    warnings: RefCell<Vec<String>>,
+}
+
+impl PassConfig {
+    /// The passes of *passes* enabled at optimization *level*
+    pub fn new(passes: &'static [Pass], level: u32) -> Self {
+        Self {
+            passes,
+            enabled: (passes.iter())
+                .filter(|pass| pass.level <= level)
+                .map(|pass| pass.name)
+                .collect(),
+            dump_after: None,
+            known_depth: if level >= MAX_LEVEL {
+                KNOWN_DEPTH_MAX_LEVEL
+            } else {
+                KNOWN_DEPTH
+            },
+            remarks: None,
+            warnings: RefCell::default(),
+        }
+    }
+
+    fn find(&self, name: &str) -> Result<&'static str, String> {
+        match self.passes.iter().find(|pass| pass.name == name) {
+            Some(pass) => Ok(pass.name),
+            None => Err(format!("unknown pass '{}'", name)),
+        }
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<(), String> {
+        let name = self.find(name)?;
+        self.enabled.insert(name);
+        Ok(())
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        let name = self.find(name)?;
+        self.enabled.remove(name);
+        Ok(())
+    }
+
+    /// Makes `dump` write the IR once pass *name* has run
+    pub fn set_dump_after(&mut self, name: &str) -> Result<(), String> {
+        self.dump_after = Some(self.find(name)?);
+        Ok(())
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    pub fn known_depth(&self) -> usize {
+        self.known_depth
+    }
+
+    /// Makes the optimizer record a remark for each loop
+    pub fn collect_remarks(&mut self) {
+        self.remarks = Some(RefCell::default());
+    }
+
+    /// Records what loop *id* was optimized to. A loop optimized more than
+    /// once keeps its last remark, which is the one its final code is from.
+    pub fn remark(&self, id: usize, remark: LoopRemark) {
+        if let Some(remarks) = &self.remarks {
+            remarks.borrow_mut().insert(id, remark);
+        }
+    }
+
+    /// Remarks collected so far, ordered by loop
+    pub fn remarks(&self) -> Vec<(usize, LoopRemark)> {
+        match &self.remarks {
+            Some(remarks) => (remarks.borrow().iter())
+                .map(|(id, remark)| (*id, remark.clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Records a problem the optimizer ran into, which didn't stop it from
+    /// producing correct code
+    pub fn warn(&self, warning: String) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Warnings recorded so far
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    /// Writes *ir* to stderr if the IR was asked for after any of *passes*,
+    /// which have just run
+    pub fn dump(&self, passes: &[&str], ir: &dyn fmt::Debug) {
+        if let Some(name) = self.dump_after {
+            if passes.contains(&name) {
+                eprintln!("; IR after {}", name);
+                eprintln!("{:#?}", ir);
+            }
+        }
+    }
+}
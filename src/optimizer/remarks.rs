@@ -0,0 +1,43 @@
+//! Remarks on what the optimizer made of each loop in the source
+//!
+//! Loops are identified by their index in a pre-order walk of the AST,
+//! which is the order their `[` appears in the source; see
+//! `parser::loop_positions`.
+
+use std::fmt;
+
+/// What a loop was optimized to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoopRemark {
+    /// The loop never runs
+    Removed,
+    /// The loop is in the body of one that never runs
+    RemovedWithEnclosing,
+    /// The loop only moves the cursor
+    Scan,
+    /// The loop adds multiples of its cell to other cells
+    MulCopy,
+    /// The loop was replaced with code that runs at most once
+    If,
+    /// The loop was replaced with straight-line arithmetic
+    Expr,
+    /// The loop never terminates, and its body has no effect
+    Infinite,
+    /// The loop was not optimized, for this reason
+    Loop(String),
+}
+
+impl fmt::Display for LoopRemark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoopRemark::Removed => write!(f, "loop removed, since it never runs"),
+            LoopRemark::RemovedWithEnclosing => write!(f, "loop removed with its enclosing loop"),
+            LoopRemark::Scan => write!(f, "loop became a scan"),
+            LoopRemark::MulCopy => write!(f, "loop became multiply-copies"),
+            LoopRemark::If => write!(f, "loop became an if"),
+            LoopRemark::Expr => write!(f, "loop became an expression"),
+            LoopRemark::Infinite => write!(f, "loop never terminates; body removed"),
+            LoopRemark::Loop(reason) => write!(f, "loop not optimized: {}", reason),
+        }
+    }
+}
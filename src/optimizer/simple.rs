@@ -2,60 +2,72 @@
 // Serves as an example optimizer implementation, and perhaps
 // useful as a reference for benchmarking and debugging.
 
-use super::Optimizer;
+use super::{Optimizer, PassConfig};
+use crate::lir::CellType;
 use crate::{LIRBuilder, AST, LIR};
 use std::io::Write;
+use std::mem;
 
 pub struct SimpleOptimizer;
 
 impl Optimizer for SimpleOptimizer {
-    fn optimize(&self, ast: &[AST], level: u32) -> Vec<LIR> {
-        let mut loopnum = 0;
+    fn optimize(&self, ast: &[AST], _passes: &PassConfig, _cell: CellType) -> Vec<LIR> {
         let mut lir = LIRBuilder::new();
-        optimize(ast, level, &mut loopnum, &mut lir);
+        lir.declare_bss_buf("strbuf", 1);
+        optimize(ast, &mut lir);
         lir.build()
     }
 
-    fn dumpir(&self, ast: &[AST], level: u32, file: &mut dyn Write) -> std::io::Result<()> {
+    fn dumpir(
+        &self,
+        ast: &[AST],
+        passes: &PassConfig,
+        cell: CellType,
+        file: &mut dyn Write,
+    ) -> std::io::Result<()> {
         // Optimizer lacks its own IR, so dump LIR
-        writeln!(file, "{:#?}", self.optimize(ast, level))
+        writeln!(file, "{:#?}", self.optimize(ast, passes, cell))
     }
 }
 
-fn optimize(ast: &[AST], level: u32, loopnum: &mut u32, lir: &mut LIRBuilder) {
+fn optimize(ast: &[AST], lir: &mut LIRBuilder) {
     use crate::lir::prelude::*;
 
-    lir.declare_bss_buf("strbuf", 1);
+    // Loops being compiled, innermost last: the rest of the AST around
+    // each, and that AST's LIR so far
+    let mut loops = Vec::new();
 
-    for i in ast {
-        match i {
-            AST::Output => {
+    let mut rest = ast.iter();
+    loop {
+        match rest.next() {
+            Some(AST::Output) => {
                 lir.mov(Buf("strbuf".into(), 0), Tape(0));
                 lir.output("strbuf", 0, 1);
             }
-            AST::Input => {
+            Some(AST::Input) => {
                 lir.input("strbuf", 0, 1);
                 lir.mov(Tape(0), Buf("strbuf".into(), 0));
             }
-            AST::Loop(ast) => {
-                *loopnum += 1;
-                let startlabel = format!("loop{}", loopnum);
-                let endlabel = format!("endloop{}", loopnum);
-
-                lir.jp(endlabel.clone());
-                lir.label(startlabel.clone());
-
-                optimize(ast, level, loopnum, lir);
-
-                lir.label(endlabel.clone());
-                lir.jnz(Tape(0), startlabel.clone());
+            Some(AST::Loop(ast)) => {
+                loops.push((mem::replace(&mut rest, ast.iter()), mem::take(lir)));
             }
-            AST::Shift(offset) => {
+            Some(AST::Shift(offset)) => {
                 lir.shift(*offset);
             }
-            AST::Add(add) => {
-                lir.add(Tape(0), Tape(0), Immediate(*add));
+            Some(AST::Add(add)) => {
+                lir.add(Tape(0), Tape(0), Immediate(i64::from(*add)));
+            }
+            Some(AST::Debug) => {
+                lir.debug_dump(0);
             }
+            None => match loops.pop() {
+                Some((outer_rest, outer)) => {
+                    rest = outer_rest;
+                    let body = mem::replace(lir, outer).build();
+                    lir.loop_(Tape(0), body);
+                }
+                None => return,
+            },
         }
     }
 }
@@ -1,18 +1,41 @@
-use super::Optimizer;
-use crate::{LIRBuilder, AST, LIR};
+use super::{Optimizer, Pass, PassConfig};
+use crate::lir::CellType;
+use crate::{AST, LIR};
 use std::collections::HashMap;
 use std::io::Write;
 use std::mem;
 
 pub struct SimpleAddOptimizer;
 
+const PASSES: &[Pass] = &[Pass {
+    name: "combine-adds",
+    level: 1,
+    description: "combine adds between shifts, input, output and loops",
+}];
+
 impl Optimizer for SimpleAddOptimizer {
-    fn optimize(&self, ast: &[AST], _level: u32) -> Vec<LIR> {
-        ir_to_lir(&ast_to_ir(ast))
+    fn passes(&self) -> &'static [Pass] {
+        PASSES
+    }
+
+    fn optimize(&self, ast: &[AST], passes: &PassConfig, _cell: CellType) -> Vec<LIR> {
+        let ir = ast_to_ir(ast, passes.enabled("combine-adds"));
+        passes.dump(&["combine-adds"], &ir);
+        ir_to_lir(&ir)
     }
 
-    fn dumpir(&self, ast: &[AST], _level: u32, file: &mut dyn Write) -> std::io::Result<()> {
-        writeln!(file, "{:#?}", ast_to_ir(ast))
+    fn dumpir(
+        &self,
+        ast: &[AST],
+        passes: &PassConfig,
+        _cell: CellType,
+        file: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        writeln!(
+            file,
+            "{:#?}",
+            ast_to_ir(ast, passes.enabled("combine-adds"))
+        )
     }
 }
 
@@ -20,99 +43,134 @@ impl Optimizer for SimpleAddOptimizer {
 enum SimpleAddIR {
     Output,
     Input,
+    Debug,
     Loop(Vec<SimpleAddIR>),
     Adds(HashMap<i32, i32>),
     Shift(i32),
 }
 
-fn ast_to_ir(ast: &[AST]) -> Vec<SimpleAddIR> {
+impl Drop for SimpleAddIR {
+    /// Drops nested loops without recursing, like `AST`
+    fn drop(&mut self) {
+        if let SimpleAddIR::Loop(body) = self {
+            let mut stack = mem::take(body);
+            while let Some(mut ir) = stack.pop() {
+                if let SimpleAddIR::Loop(body) = &mut ir {
+                    stack.append(body);
+                }
+            }
+        }
+    }
+}
+
+/// Converts *ast* to IR. If *combine* is set, shifts are deferred until
+/// input, output, `Debug` or a loop, so adds at several offsets are combined.
+fn ast_to_ir(ast: &[AST], combine: bool) -> Vec<SimpleAddIR> {
+    // Loops being converted, innermost last: the rest of the AST around
+    // each, and that AST's IR so far
+    let mut loops = Vec::new();
+
     let mut shift = 0;
+    let mut rest = ast.iter();
     let mut ir = Vec::new();
     let mut adds = HashMap::new();
 
-    for i in ast {
-        match i {
-            AST::Output => {
+    loop {
+        match rest.next() {
+            Some(AST::Output) => {
                 ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
                 ir.push(SimpleAddIR::Shift(shift));
                 shift = 0;
                 ir.push(SimpleAddIR::Output)
             }
-            AST::Input => {
+            Some(AST::Input) => {
                 ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
                 ir.push(SimpleAddIR::Shift(shift));
                 shift = 0;
                 ir.push(SimpleAddIR::Input);
             }
-            AST::Loop(inner) => {
+            Some(AST::Debug) => {
+                ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
+                ir.push(SimpleAddIR::Shift(shift));
+                shift = 0;
+                ir.push(SimpleAddIR::Debug);
+            }
+            Some(AST::Loop(inner)) => {
                 ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
                 ir.push(SimpleAddIR::Shift(shift));
                 shift = 0;
-                ir.push(SimpleAddIR::Loop(ast_to_ir(inner)));
+                loops.push((mem::replace(&mut rest, inner.iter()), mem::take(&mut ir)));
+            }
+            Some(AST::Shift(offset)) if !combine => {
+                ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
+                ir.push(SimpleAddIR::Shift(*offset));
             }
-            AST::Shift(offset) => {
+            Some(AST::Shift(offset)) => {
                 shift += offset;
             }
-            AST::Add(add) => {
+            Some(AST::Add(add)) => {
                 *adds.entry(shift).or_insert(0) += *add;
             }
+            None => {
+                ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
+                ir.push(SimpleAddIR::Shift(shift));
+                shift = 0;
+                match loops.pop() {
+                    Some((outer_rest, outer)) => {
+                        rest = outer_rest;
+                        let body = mem::replace(&mut ir, outer);
+                        ir.push(SimpleAddIR::Loop(body));
+                    }
+                    None => return ir,
+                }
+            }
         }
     }
-
-    ir.push(SimpleAddIR::Adds(mem::take(&mut adds)));
-    ir.push(SimpleAddIR::Shift(shift));
-
-    ir
-}
-
-#[derive(Default)]
-struct CompileState {
-    lir: LIRBuilder,
-    loopnum: i32,
 }
 
 fn ir_to_lir(ir: &[SimpleAddIR]) -> Vec<LIR> {
-    let mut state = CompileState::default();
-    state.lir.declare_bss_buf("strbuf", 1);
-    _ir_to_lir(ir, &mut state);
-    state.lir.build()
-}
-
-fn _ir_to_lir(ir: &[SimpleAddIR], state: &mut CompileState) {
     use crate::lir::prelude::*;
 
-    for i in ir {
-        match i {
-            SimpleAddIR::Output => {
-                state.lir.mov(Buf("strbuf".into(), 0), Tape(0));
-                state.lir.output("strbuf", 0, 1);
-            }
-            SimpleAddIR::Input => {
-                state.lir.input("strbuf", 0, 1);
-                state.lir.mov(Tape(0), Buf("strbuf".into(), 0));
-            }
-            SimpleAddIR::Loop(inner) => {
-                state.loopnum += 1;
-                let startlabel = format!("loop{}", state.loopnum);
-                let endlabel = format!("endloop{}", state.loopnum);
-                state.lir.jp(endlabel.clone());
-                state.lir.label(startlabel.clone());
+    let mut lir = LIRBuilder::new();
+    lir.declare_bss_buf("strbuf", 1);
 
-                _ir_to_lir(inner, state);
+    // Loops being compiled, innermost last: the rest of the IR around
+    // each, and that IR's LIR so far
+    let mut loops = Vec::new();
 
-                state.lir.label(endlabel.clone());
-                state.lir.jnz(Tape(0), startlabel.clone());
+    let mut rest = ir.iter();
+    loop {
+        match rest.next() {
+            Some(SimpleAddIR::Output) => {
+                lir.mov(Buf("strbuf".into(), 0), Tape(0));
+                lir.output("strbuf", 0, 1);
             }
-            SimpleAddIR::Adds(adds) => {
+            Some(SimpleAddIR::Input) => {
+                lir.input("strbuf", 0, 1);
+                lir.mov(Tape(0), Buf("strbuf".into(), 0));
+            }
+            Some(SimpleAddIR::Debug) => {
+                lir.debug_dump(0);
+            }
+            Some(SimpleAddIR::Loop(inner)) => {
+                loops.push((mem::replace(&mut rest, inner.iter()), mem::take(&mut lir)));
+            }
+            Some(SimpleAddIR::Adds(adds)) => {
                 for (offset, value) in adds {
-                    state
-                        .lir
-                        .add(Tape(*offset), Tape(*offset), Immediate(*value));
+                    lir.add(Tape(*offset), Tape(*offset), Immediate(i64::from(*value)));
                 }
             }
-            SimpleAddIR::Shift(shift) => {
-                state.lir.shift(*shift);
+            Some(SimpleAddIR::Shift(shift)) => {
+                lir.shift(*shift);
             }
+            None => match loops.pop() {
+                Some((outer_rest, outer)) => {
+                    rest = outer_rest;
+                    let body = mem::replace(&mut lir, outer).build();
+                    lir.loop_(Tape(0), body);
+                }
+                None => return lir.build(),
+            },
         }
     }
 }
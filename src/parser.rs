@@ -1,137 +1,797 @@
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::ops::Range;
 use unicode_width::UnicodeWidthStr;
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum AST {
     Output,
     Input,
     Loop(Vec<AST>),
     Add(i32),
     Shift(i32),
+    /// `#`, an extension that writes the cells around the cursor to stderr
+    Debug,
+}
+
+impl Clone for AST {
+    /// Copies nested loops from a stack, rather than recursively, like `drop`
+    fn clone(&self) -> Self {
+        let body = match self {
+            AST::Loop(body) => body,
+            AST::Output => return AST::Output,
+            AST::Input => return AST::Input,
+            AST::Add(add) => return AST::Add(*add),
+            AST::Shift(offset) => return AST::Shift(*offset),
+            AST::Debug => return AST::Debug,
+        };
+        // Loops being copied, innermost last: the rest of the body around
+        // each, and that body's copy so far
+        let mut loops = Vec::new();
+
+        let mut rest = body.iter();
+        let mut copy = Vec::new();
+        loop {
+            match rest.next() {
+                Some(AST::Loop(inner)) => {
+                    loops.push((mem::replace(&mut rest, inner.iter()), mem::take(&mut copy)));
+                }
+                Some(ast) => copy.push(ast.clone()),
+                None => match loops.pop() {
+                    Some((outer_rest, outer)) => {
+                        rest = outer_rest;
+                        let body = mem::replace(&mut copy, outer);
+                        copy.push(AST::Loop(body));
+                    }
+                    None => return AST::Loop(copy),
+                },
+            }
+        }
+    }
+}
+
+impl Drop for AST {
+    /// Drops nested loops from a stack, rather than recursively, so deeply
+    /// nested programs can't overflow the stack
+    fn drop(&mut self) {
+        if let AST::Loop(body) = self {
+            let mut stack = mem::take(body);
+            while let Some(mut ast) = stack.pop() {
+                if let AST::Loop(body) = &mut ast {
+                    stack.append(body);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ParseErrorType {
     UnclosedLoop,
     ExtraCloseLoop,
+    /// Reading the code failed, when parsing with `StreamParser`
+    Io(io::Error),
 }
 use ParseErrorType::*;
 
+impl ParseErrorType {
+    /// A code identifying the kind of error, which doesn't change between
+    /// versions
+    pub fn code(&self) -> &'static str {
+        match self {
+            UnclosedLoop => "E001",
+            ExtraCloseLoop => "E002",
+            Io(_) => "E003",
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnclosedLoop => write!(f, "[ is never closed"),
+            ExtraCloseLoop => write!(f, "] found when not in a loop"),
+            Io(err) => write!(f, "failed to read code: {}", err),
+        }
+    }
+}
+
+/// Bytes of a line shown on each side of a bracket in errors, so that
+/// `StreamParser` doesn't have to keep long lines in memory
+const CONTEXT: usize = 40;
+
+/// An unmatched bracket, or failure to read the code
 #[derive(Debug)]
 pub struct ParseError {
     err: ParseErrorType,
+    /// Part of the line around the bracket, up to `CONTEXT` bytes on each
+    /// side of it, with `...` where the line goes on
     line: Vec<u8>,
+    /// Line number, starting at 1
     linenum: usize,
+    /// Offset in bytes of the bracket in *line*
     offset: usize,
+    /// Column of the bracket in characters, starting at 1
+    column: usize,
+    /// Offset in bytes of the bracket in the code
+    pos: usize,
+    /// Length in bytes of the bracket, which is longer in other dialects
+    len: usize,
 }
 
 impl ParseError {
-    fn new(err: ParseErrorType, code: &[u8], i: usize) -> Self {
-        let (line, linenum, offset) = find_line(code, i);
+    /// The error for the bracket at *span* in *code*, with the context
+    /// `Scanner` would give it, from reading its line the same way
+    fn new(err: ParseErrorType, code: &[u8], span: Range<usize>) -> Self {
+        let (line, linenum, offset) = find_line(code, span.start);
+        let mut context = LineContext::new(linenum + 1, span.start - offset);
+        for &c in &line[..offset] {
+            context.push(c);
+        }
+        // A bracket in another dialect may go on past the end of the line
+        let end = (offset + span.len()).min(line.len());
+        let mut error = context.error(err, &line[offset..end]);
+        error.len = span.len();
+
+        let bracket_end = span.start - offset + end;
+        for &c in &line[offset..] {
+            if context.finish(&mut error, bracket_end, true) {
+                return error;
+            }
+            context.push(c);
+        }
+        context.finish(&mut error, bracket_end, false);
+        error
+    }
+
+    /// The error for the bracket at *span* in *code*, which the code this
+    /// error was found in was translated from
+    pub(crate) fn relocate(self, code: &[u8], span: Range<usize>) -> Self {
+        Self::new(self.err, code, span)
+    }
+
+    pub fn kind(&self) -> &ParseErrorType {
+        &self.err
+    }
+
+    /// Line of the bracket, starting at 1
+    pub fn line(&self) -> usize {
+        self.linenum
+    }
+
+    /// Column of the bracket in characters, starting at 1
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Byte range of the bracket in the code
+    pub fn span(&self) -> Range<usize> {
+        self.pos..self.pos + self.len
+    }
+}
+
+fn is_continuation(c: u8) -> bool {
+    c & 0xc0 == 0x80
+}
+
+/// *before*, which is the end of a line up to a bracket, for an error's
+/// context. If the line starts before it, as *cut* says, any partial
+/// character at its start is removed and `...` is put in front.
+fn context_before(before: &[u8], cut: bool) -> Vec<u8> {
+    if !cut {
+        return before.to_vec();
+    }
+    let start = before.iter().take_while(|c| is_continuation(**c)).count();
+    [b"...", &before[start..]].concat()
+}
+
+/// *after*, which is the start of a line after a bracket, for an error's
+/// context. If the line goes on after it, as *cut* says, any partial
+/// character at its end is removed and `...` is put after it.
+fn context_after(after: &[u8], cut: bool) -> Vec<u8> {
+    if !cut {
+        return after.to_vec();
+    }
+    let mut end = after.len();
+    if let Some(lead) = after.iter().rposition(|c| !is_continuation(*c)) {
+        let len = match after[lead] {
+            0xf0.. => 4,
+            0xe0.. => 3,
+            0xc0.. => 2,
+            _ => 1,
+        };
+        if lead + len > after.len() {
+            end = lead;
+        }
+    }
+    [&after[..end], b"..."].concat()
+}
+
+/// The line being read, as much of it as errors on it show. `Scanner` and
+/// `ParseError::new` both build the context of errors with it, so an error
+/// shows the same context however it was found.
+struct LineContext {
+    /// Line number, starting at 1
+    linenum: usize,
+    /// Position in the code of the line's first byte
+    start: usize,
+    /// Number of bytes read of the line
+    len: usize,
+    /// Number of characters read of the line
+    column: usize,
+    /// The last `CONTEXT` bytes read of the line
+    recent: VecDeque<u8>,
+}
+
+impl LineContext {
+    fn new(linenum: usize, start: usize) -> Self {
         Self {
-            err,
-            line: line.into(),
             linenum,
+            start,
+            len: 0,
+            column: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Position in the code of the next byte of the line
+    fn pos(&self) -> usize {
+        self.start + self.len
+    }
+
+    /// Reads *c*, the next byte of the line
+    fn push(&mut self, c: u8) {
+        self.len += 1;
+        if !is_continuation(c) {
+            self.column += 1;
+        }
+        if self.recent.len() == CONTEXT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(c);
+    }
+
+    /// An error for *bracket*, which comes next on the line, with the
+    /// context before it. The context after it is added by `finish`.
+    fn error(&self, err: ParseErrorType, bracket: &[u8]) -> ParseError {
+        let before = self.recent.iter().copied().collect::<Vec<_>>();
+        let mut line = context_before(&before, self.len > before.len());
+        let offset = line.len();
+        line.extend_from_slice(bracket);
+        ParseError {
+            err,
+            line,
+            linenum: self.linenum,
             offset,
+            column: self.column + 1,
+            pos: self.pos(),
+            len: bracket.len(),
         }
     }
+
+    /// Adds the context after the bracket of *err*, which ends at *end*,
+    /// once it is read, returning whether it was. If *more*, the line goes
+    /// on past what has been read, and the context is only finished once
+    /// `CONTEXT` bytes after the bracket are; otherwise the line ends here.
+    fn finish(&self, err: &mut ParseError, end: usize, more: bool) -> bool {
+        let after_len = match self.pos().checked_sub(end) {
+            Some(after_len) if !more || after_len >= CONTEXT => after_len,
+            _ => return false,
+        };
+        let after = self.recent.range(self.recent.len() - after_len..);
+        err.line
+            .extend(context_after(&after.copied().collect::<Vec<_>>(), more));
+        true
+    }
 }
 
 impl fmt::Display for ParseError {
+    /// Shows the error as `line:column: error[code]: message`, followed by
+    /// the line with the bracket marked
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let line = String::from_utf8_lossy(&self.line);
-        let width = UnicodeWidthStr::width(&line[0..self.offset]);
+        if let Io(_) = self.err {
+            return write!(f, "error[{}]: {}", self.err.code(), self.err);
+        }
 
-        match self.err {
-            UnclosedLoop => {
-                writeln!(f, "reached EOF with unterminated loop")?;
-                writeln!(f, "Loop started at {}:{}", self.linenum, self.offset)?;
-            }
-            ExtraCloseLoop => {
-                writeln!(
-                    f,
-                    "[ found at {}:{} when not in a loop",
-                    self.linenum, self.offset
-                )?;
-            }
-        };
+        writeln!(
+            f,
+            "{}:{}: error[{}]: {}",
+            self.linenum,
+            self.column(),
+            self.err.code(),
+            self.err
+        )?;
+        write_marked_line(f, &self.line, self.offset, self.len)
+    }
+}
 
-        writeln!(f, "{}", line)?;
-        write!(f, "{}^", " ".repeat(width))?;
+impl Error for ParseError {}
 
-        Ok(())
+/// An item of a program in flat form, with loops as matching start and end
+/// items rather than nested `Vec`s. Runs of adds and shifts are combined as
+/// in `AST`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlatAST {
+    Output,
+    Input,
+    /// `LoopStart(end)` Starts a loop, whose `LoopEnd` is at index *end*
+    LoopStart(usize),
+    /// `LoopEnd(start)` Ends the loop started at index *start*
+    LoopEnd(usize),
+    Add(i32),
+    Shift(i32),
+    Debug,
+}
+
+/// Collects the AST *parser* returns, or all the errors if there are any
+fn collect<R: Read>(parser: &mut StreamParser<R>) -> Result<Vec<AST>, Vec<ParseError>> {
+    let mut ast = Vec::new();
+    let mut errors = Vec::new();
+    for i in parser {
+        match i {
+            Ok(i) => ast.push(i),
+            Err(err) => errors.push(err),
+        }
+    }
+    if errors.is_empty() {
+        Ok(ast)
+    } else {
+        Err(errors)
     }
 }
 
-impl Error for ParseError {}
+/// Parses a string of brainfuck code to unoptimized AST. If brackets don't
+/// match, returns an error for each unmatched one.
+pub fn parse(code: &[u8]) -> Result<Vec<AST>, Vec<ParseError>> {
+    collect(&mut StreamParser::new(code))
+}
 
-/// Parses a string of brainfuck code to unoptimized AST
-pub fn parse(code: &[u8]) -> Result<Vec<AST>, ParseError> {
-    let mut i = 0;
-    _parse(code, &mut i, 0)
+/// A program, and the input embedded in it after a `!`
+type WithInput<T> = (Vec<T>, Option<Vec<u8>>);
+
+/// Parses code like `parse`, with the extensions interpreters commonly
+/// support: `#` writes the cells around the cursor to stderr, and anything
+/// after the first `!` is input for the program, rather than code. Returns
+/// that input, if there is a `!`.
+///
+/// # Examples
+/// ```
+/// let (ast, input) = isbfc::parse_extended(b",[.,]#!abc").unwrap();
+/// assert!(matches!(ast.last(), Some(isbfc::AST::Debug)));
+/// assert_eq!(input.as_deref(), Some(&b"abc"[..]));
+/// ```
+pub fn parse_extended(code: &[u8]) -> Result<WithInput<AST>, Vec<ParseError>> {
+    let mut parser = StreamParser::new(code).with_extensions();
+    let ast = collect(&mut parser)?;
+    // Reading a slice can't fail
+    let input = parser.read_input().unwrap();
+    Ok((ast, input))
+}
+
+/// Parses brainfuck code to unoptimized AST as it is read, returning each
+/// top level item once the loops in it are closed. Only the loops still
+/// open, and a little of the line around each one's `[`, are kept in
+/// memory.
+///
+/// Parsing continues after an unmatched `]`, ignoring it, and the error for
+/// it is returned at the end of its line. Errors for unclosed loops are
+/// returned at the end of the code.
+///
+/// # Examples
+/// ```
+/// use isbfc::{StreamParser, AST};
+///
+/// let mut parser = StreamParser::new(&b",[.,]"[..]);
+/// assert!(matches!(parser.next(), Some(Ok(AST::Input))));
+/// assert!(matches!(parser.next(), Some(Ok(AST::Loop(_)))));
+/// assert!(parser.next().is_none());
+/// ```
+pub struct StreamParser<R: Read> {
+    scanner: Scanner<BufReader<R>>,
+    /// Enclosing bodies of the loops being parsed, innermost last
+    loops: Vec<Vec<AST>>,
+    /// Body of the innermost loop being parsed
+    body: Vec<AST>,
 }
 
-fn _parse(code: &[u8], i: &mut usize, level: u32) -> Result<Vec<AST>, ParseError> {
-    // Starting [ of the loop
-    let start = i.saturating_sub(1);
+impl<R: Read> StreamParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            scanner: Scanner::new(BufReader::new(reader)),
+            loops: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Parses `#` and `!` like `parse_extended`. The code ends at the first
+    /// `!`, and `read_input` reads what follows it.
+    pub fn with_extensions(mut self) -> Self {
+        self.scanner.extensions = true;
+        self
+    }
 
-    let mut shift = 0;
-    let mut add = 0;
+    /// Once parsing is finished, reads the input after the `!` that ended
+    /// the code, or returns `None` if it didn't end with one
+    pub fn read_input(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.scanner.read_input()
+    }
+}
 
-    let mut tokens = Vec::new();
-    while let Some(c) = code.get(*i) {
-        *i += 1;
+impl<R: Read> Iterator for StreamParser<R> {
+    type Item = Result<AST, ParseError>;
 
-        if shift != 0 && !b"><".contains(c) {
-            tokens.push(AST::Shift(shift));
-            shift = 0;
-        } else if add != 0 && !b"+-".contains(c) {
-            tokens.push(AST::Add(add));
-            add = 0;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ast = match self.scanner.next()? {
+                Ok(Command::Output) => AST::Output,
+                Ok(Command::Input) => AST::Input,
+                Ok(Command::LoopStart) => {
+                    self.loops.push(mem::take(&mut self.body));
+                    continue;
+                }
+                Ok(Command::LoopEnd) => {
+                    // The scanner only returns ends of open loops
+                    let outer = self.loops.pop().unwrap();
+                    AST::Loop(mem::replace(&mut self.body, outer))
+                }
+                Ok(Command::Add(add)) => AST::Add(add),
+                Ok(Command::Shift(shift)) => AST::Shift(shift),
+                Ok(Command::Debug) => AST::Debug,
+                Err(err) => return Some(Err(err)),
+            };
+            if self.loops.is_empty() {
+                return Some(Ok(ast));
+            }
+            self.body.push(ast);
+        }
+    }
+}
+
+/// A command found by `Scanner`
+enum Command {
+    Output,
+    Input,
+    LoopStart,
+    /// A `]` that closes an open loop
+    LoopEnd,
+    Add(i32),
+    Shift(i32),
+    Debug,
+}
+
+/// Reads brainfuck code, returning its commands, with runs of adds and
+/// shifts combined, and errors for unmatched brackets. `StreamParser` and
+/// `parse_flat` both parse with it, so they find the same errors.
+struct Scanner<R: BufRead> {
+    bytes: io::Bytes<R>,
+    /// Errors for the loops still open, innermost last, if they are never
+    /// closed
+    loops: Vec<ParseError>,
+    /// Commands and errors found but not yet returned
+    ready: VecDeque<Result<Command, ParseError>>,
+    shift: i32,
+    add: i32,
+    /// The current line
+    line: LineContext,
+    /// Positions of brackets on the current line, in order, whose errors
+    /// are still missing the context after them
+    unfinished: VecDeque<usize>,
+    /// Errors on the current line, returned once the rest of it is read
+    line_errors: Vec<ParseError>,
+    /// Whether `#` and `!` are parsed, as in `parse_extended`
+    extensions: bool,
+    /// Whether the code ended with a `!`, which the input follows
+    separated: bool,
+    done: bool,
+}
+
+impl<R: BufRead> Scanner<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            bytes: reader.bytes(),
+            loops: Vec::new(),
+            ready: VecDeque::new(),
+            shift: 0,
+            add: 0,
+            line: LineContext::new(1, 0),
+            unfinished: VecDeque::new(),
+            line_errors: Vec::new(),
+            extensions: false,
+            separated: false,
+            done: false,
+        }
+    }
+
+    /// Once scanning is finished, reads the input after the `!` that ended
+    /// the code, or returns `None` if it didn't end with one
+    fn read_input(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.separated {
+            return Ok(None);
+        }
+        self.bytes.by_ref().collect::<io::Result<_>>().map(Some)
+    }
+
+    /// An error for bracket *c*, the next byte of the current line. The
+    /// context after it is added by `finish_contexts`, once it is read.
+    fn bracket_error(&mut self, err: ParseErrorType, c: u8) -> ParseError {
+        let err = self.line.error(err, &[c]);
+        self.unfinished.push_back(err.pos);
+        err
+    }
+
+    /// Adds the context after brackets to their errors, once it is read.
+    /// If *more*, the line goes on past the current position; otherwise it
+    /// ends there.
+    fn finish_contexts(&mut self, more: bool) {
+        while let Some(&pos) = self.unfinished.front() {
+            // The loop may have been closed since, with no error left
+            let err = match self.line_errors.binary_search_by_key(&pos, |err| err.pos) {
+                Ok(i) => &mut self.line_errors[i],
+                Err(_) => match self.loops.binary_search_by_key(&pos, |err| err.pos) {
+                    Ok(i) => &mut self.loops[i],
+                    Err(_) => {
+                        self.unfinished.pop_front();
+                        continue;
+                    }
+                },
+            };
+            if !self.line.finish(err, pos + 1, more) {
+                break;
+            }
+            self.unfinished.pop_front();
+        }
+    }
+
+    /// Returns the errors on the current line, now that all of it is read
+    fn end_line(&mut self) {
+        self.finish_contexts(false);
+        for err in self.line_errors.drain(..) {
+            self.ready.push_back(Err(err));
+        }
+    }
+
+    /// Scans *c*, the next byte of the current line
+    fn scan_byte(&mut self, c: u8) {
+        if c != b'\n' {
+            self.finish_contexts(true);
+        }
+
+        if self.shift != 0 && !b"><".contains(&c) {
+            let shift = mem::take(&mut self.shift);
+            self.ready.push_back(Ok(Command::Shift(shift)));
+        } else if self.add != 0 && !b"+-".contains(&c) {
+            let add = mem::take(&mut self.add);
+            self.ready.push_back(Ok(Command::Add(add)));
         }
 
         match c {
             b'+' => {
-                add += 1;
+                self.add += 1;
             }
             b'-' => {
-                add -= 1;
+                self.add -= 1;
             }
             b'>' => {
-                shift += 1;
+                self.shift += 1;
             }
             b'<' => {
-                shift -= 1;
-            }
-            b'[' => tokens.push(AST::Loop(_parse(code, i, level + 1)?)),
-            b']' => {
-                if shift != 0 {
-                    tokens.push(AST::Shift(shift));
-                } else if add != 0 {
-                    tokens.push(AST::Add(add));
+                self.shift -= 1;
+            }
+            b'[' => {
+                let err = self.bracket_error(UnclosedLoop, c);
+                self.loops.push(err);
+                self.ready.push_back(Ok(Command::LoopStart));
+            }
+            b']' => match self.loops.pop() {
+                Some(_) => self.ready.push_back(Ok(Command::LoopEnd)),
+                None => {
+                    let err = self.bracket_error(ExtraCloseLoop, c);
+                    self.line_errors.push(err);
                 }
-
-                return if level == 0 {
-                    Err(ParseError::new(ExtraCloseLoop, code, *i - 1))
-                } else {
-                    Ok(tokens)
-                };
+            },
+            b',' => self.ready.push_back(Ok(Command::Input)),
+            b'.' => self.ready.push_back(Ok(Command::Output)),
+            b'#' if self.extensions => self.ready.push_back(Ok(Command::Debug)),
+            b'!' if self.extensions => {
+                self.separated = true;
+                self.done = true;
+                self.end();
+                return;
+            }
+            b'\n' => {
+                self.end_line();
+                self.line = LineContext::new(self.line.linenum + 1, self.line.pos() + 1);
+                return;
             }
-            b',' => tokens.push(AST::Input),
-            b'.' => tokens.push(AST::Output),
             _ => (),
         };
+
+        self.line.push(c);
+    }
+
+    /// Returns errors for the loops that are still open
+    fn end(&mut self) {
+        self.end_line();
+        for err in mem::take(&mut self.loops) {
+            self.ready.push_back(Err(err));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Scanner<R> {
+    type Item = Result<Command, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() && !self.done {
+            match self.bytes.next() {
+                Some(Ok(c)) => self.scan_byte(c),
+                Some(Err(err)) => {
+                    self.done = true;
+                    self.ready.push_back(Err(ParseError {
+                        err: Io(err),
+                        line: Vec::new(),
+                        linenum: self.line.linenum,
+                        offset: 0,
+                        column: self.line.column + 1,
+                        pos: self.line.pos(),
+                        len: 0,
+                    }));
+                }
+                None => {
+                    self.done = true;
+                    self.end();
+                }
+            }
+        }
+        self.ready.pop_front()
+    }
+}
+
+/// Collects the flat form of the code *scanner* reads, or all the errors if
+/// there are any
+fn collect_flat<R: BufRead>(scanner: &mut Scanner<R>) -> Result<Vec<FlatAST>, Vec<ParseError>> {
+    // Indices in *flat* of the loops being parsed, innermost last
+    let mut loops = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut flat = Vec::new();
+    for command in scanner {
+        match command {
+            Ok(Command::Output) => flat.push(FlatAST::Output),
+            Ok(Command::Input) => flat.push(FlatAST::Input),
+            Ok(Command::LoopStart) => {
+                loops.push(flat.len());
+                // The end isn't known until it is parsed
+                flat.push(FlatAST::LoopStart(0));
+            }
+            Ok(Command::LoopEnd) => {
+                let start = loops.pop().unwrap();
+                flat[start] = FlatAST::LoopStart(flat.len());
+                flat.push(FlatAST::LoopEnd(start));
+            }
+            Ok(Command::Add(add)) => flat.push(FlatAST::Add(add)),
+            Ok(Command::Shift(shift)) => flat.push(FlatAST::Shift(shift)),
+            Ok(Command::Debug) => flat.push(FlatAST::Debug),
+            Err(err) => errors.push(err),
+        }
     }
 
-    if level != 0 {
-        Err(ParseError::new(UnclosedLoop, code, start))
+    if errors.is_empty() {
+        Ok(flat)
     } else {
-        Ok(tokens)
+        Err(errors)
+    }
+}
+
+/// Parses a string of brainfuck code to unoptimized AST in flat form. If
+/// brackets don't match, returns an error for each unmatched one.
+///
+/// # Examples
+/// ```
+/// use isbfc::FlatAST::*;
+///
+/// let flat = isbfc::parse_flat(b"+[->++<]").unwrap();
+/// assert_eq!(flat, [Add(1), LoopStart(6), Add(-1), Shift(1), Add(2), Shift(-1), LoopEnd(1)]);
+/// ```
+pub fn parse_flat(code: &[u8]) -> Result<Vec<FlatAST>, Vec<ParseError>> {
+    collect_flat(&mut Scanner::new(code))
+}
+
+/// Parses code in flat form like `parse_flat`, with the extensions
+/// `parse_extended` supports, returning the input after the `!` too
+pub fn parse_flat_extended(code: &[u8]) -> Result<WithInput<FlatAST>, Vec<ParseError>> {
+    let mut scanner = Scanner::new(code);
+    scanner.extensions = true;
+    let flat = collect_flat(&mut scanner)?;
+    // Reading a slice can't fail
+    let input = scanner.read_input().unwrap();
+    Ok((flat, input))
+}
+
+/// Converts *ast* to flat form
+pub fn ast_to_flat(ast: &[AST]) -> Vec<FlatAST> {
+    // Loops being converted, innermost last: the rest of the body around
+    // each, and the loop's index in *flat*
+    let mut loops = Vec::new();
+
+    let mut rest = ast.iter();
+    let mut flat = Vec::new();
+    loop {
+        match rest.next() {
+            Some(AST::Output) => flat.push(FlatAST::Output),
+            Some(AST::Input) => flat.push(FlatAST::Input),
+            Some(AST::Loop(inner)) => {
+                loops.push((mem::replace(&mut rest, inner.iter()), flat.len()));
+                flat.push(FlatAST::LoopStart(0));
+            }
+            Some(AST::Add(add)) => flat.push(FlatAST::Add(*add)),
+            Some(AST::Shift(offset)) => flat.push(FlatAST::Shift(*offset)),
+            Some(AST::Debug) => flat.push(FlatAST::Debug),
+            None => match loops.pop() {
+                Some((outer_rest, start)) => {
+                    rest = outer_rest;
+                    flat[start] = FlatAST::LoopStart(flat.len());
+                    flat.push(FlatAST::LoopEnd(start));
+                }
+                None => return flat,
+            },
+        }
+    }
+}
+
+/// Converts *flat*, which must have matching loop starts and ends, to AST
+pub fn flat_to_ast(flat: &[FlatAST]) -> Vec<AST> {
+    // Enclosing bodies of the loops being converted, innermost last
+    let mut loops = Vec::new();
+
+    let mut ast = Vec::new();
+    for i in flat {
+        match *i {
+            FlatAST::Output => ast.push(AST::Output),
+            FlatAST::Input => ast.push(AST::Input),
+            FlatAST::LoopStart(_) => loops.push(mem::take(&mut ast)),
+            FlatAST::LoopEnd(_) => {
+                let outer = loops.pop().expect("unmatched loop end");
+                let body = mem::replace(&mut ast, outer);
+                ast.push(AST::Loop(body));
+            }
+            FlatAST::Add(add) => ast.push(AST::Add(add)),
+            FlatAST::Shift(offset) => ast.push(AST::Shift(offset)),
+            FlatAST::Debug => ast.push(AST::Debug),
+        }
     }
+    assert!(loops.is_empty(), "unmatched loop start");
+    ast
 }
 
-fn find_line(code: &[u8], i: usize) -> (&[u8], usize, usize) {
+/// Line and column, both starting at 1, of each `[` in *code*. The index
+/// of a loop's position is its index in a pre-order walk of the AST.
+pub fn loop_positions(code: &[u8]) -> Vec<(usize, usize)> {
+    let mut positions = Vec::new();
+    let (mut line, mut column) = (1, 1);
+    for c in code {
+        match c {
+            b'[' => positions.push((line, column)),
+            b'\n' => {
+                line += 1;
+                column = 0;
+            }
+            _ => {}
+        }
+        // Count characters rather than bytes of UTF-8
+        if c & 0xc0 != 0x80 {
+            column += 1;
+        }
+    }
+    positions
+}
+
+/// The line *i* is on in *code*, its line number starting at 0, and the
+/// offset of *i* in it
+pub(crate) fn find_line(code: &[u8], i: usize) -> (&[u8], usize, usize) {
     let offset = code[0..i].iter().rev().take_while(|x| **x != b'\n').count();
     let end = i + code[i..].iter().take_while(|x| **x != b'\n').count();
     let linenum = code[0..(i - offset)]
@@ -140,3 +800,189 @@ fn find_line(code: &[u8], i: usize) -> (&[u8], usize, usize) {
         .count();
     (&code[(i - offset)..end], linenum, offset)
 }
+
+/// Column in characters, starting at 1, of *offset* in *line*
+pub(crate) fn column(line: &[u8], offset: usize) -> usize {
+    // Count characters rather than bytes of UTF-8, like `loop_positions`
+    let chars = line[..offset].iter().filter(|c| *c & 0xc0 != 0x80).count();
+    chars + 1
+}
+
+/// Writes *line*, with the *len* bytes at *offset* marked on the line below
+/// it, as far as the end of the line
+pub(crate) fn write_marked_line(
+    f: &mut fmt::Formatter,
+    line: &[u8],
+    offset: usize,
+    len: usize,
+) -> fmt::Result {
+    let before = String::from_utf8_lossy(&line[..offset]);
+    // Keep tabs, which are as wide as the terminal shows them
+    let padding = (before.split('\t'))
+        .map(|part| " ".repeat(UnicodeWidthStr::width(part)))
+        .collect::<Vec<_>>()
+        .join("\t");
+    let marked = String::from_utf8_lossy(&line[offset..line.len().min(offset + len)]);
+    let marked_width = UnicodeWidthStr::width(&*marked).max(1);
+    writeln!(f, "{}", String::from_utf8_lossy(line))?;
+    write!(f, "{}{}", padding, "^".repeat(marked_width))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_line_context() {
+        let code = "+".repeat(1000) + "[" + &"-".repeat(1000);
+        let errors = parse(code.as_bytes()).unwrap_err();
+        let context = "+".repeat(CONTEXT) + "[" + &"-".repeat(CONTEXT);
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "1:1001: error[E001]: [ is never closed\n...{}...\n{}^",
+                context,
+                " ".repeat(CONTEXT + 3)
+            )
+        );
+    }
+
+    #[test]
+    fn stream_context_matches_code_context() {
+        // Multibyte characters, which the context cuts in the middle of
+        let code = "€".repeat(30) + "]" + &"€".repeat(30) + "\n" + &"é".repeat(5) + "[ü";
+        let stream = parse(code.as_bytes()).unwrap_err();
+        assert_eq!(stream.len(), 2);
+        for err in &stream {
+            let kind = match err.kind() {
+                UnclosedLoop => UnclosedLoop,
+                _ => ExtraCloseLoop,
+            };
+            let found = ParseError::new(kind, code.as_bytes(), err.span());
+            assert_eq!(err.to_string(), found.to_string());
+            assert_eq!(err.span(), found.span());
+        }
+        assert_eq!(
+            stream[0].to_string(),
+            format!(
+                "1:31: error[E002]: ] found when not in a loop\n...{}]{}...\n{}^",
+                "€".repeat(13),
+                "€".repeat(13),
+                " ".repeat(16)
+            )
+        );
+        assert_eq!(
+            stream[1].to_string(),
+            "2:6: error[E001]: [ is never closed\nééééé[ü\n     ^"
+        );
+    }
+
+    #[test]
+    fn every_unmatched_bracket() {
+        let errors = parse(b"]+]\n[-\n[").unwrap_err();
+        let found = (errors.iter())
+            .map(|err| (err.kind().code(), err.line(), err.column(), err.span()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            [
+                ("E002", 1, 1, 0..1),
+                ("E002", 1, 3, 2..3),
+                ("E001", 2, 1, 4..5),
+                ("E001", 3, 1, 7..8),
+            ]
+        );
+    }
+
+    #[test]
+    fn tab_before_bracket() {
+        let errors = parse(b"+\t- \t]").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "1:6: error[E002]: ] found when not in a loop\n+\t- \t]\n \t  \t^"
+        );
+    }
+
+    /// Reads some code, then fails
+    struct FailingReader(&'static [u8]);
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::other("disk on fire"));
+            }
+            let len = self.0.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn read_error() {
+        let mut parser = StreamParser::new(FailingReader(b"+\n[-"));
+        assert!(matches!(parser.next(), Some(Ok(AST::Add(1)))));
+        let err = parser.next().unwrap().unwrap_err();
+        assert!(matches!(err.kind(), Io(_)));
+        assert_eq!(
+            err.to_string(),
+            "error[E003]: failed to read code: disk on fire"
+        );
+        assert_eq!(err.span(), 4..4);
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn empty_embedded_input() {
+        let (ast, input) = parse_extended(b",.!").unwrap();
+        assert_eq!(ast.len(), 2);
+        assert_eq!(input.as_deref(), Some(&b""[..]));
+
+        let (_, input) = parse_extended(b",.").unwrap();
+        assert_eq!(input, None);
+    }
+
+    #[test]
+    fn embedded_input_is_not_code() {
+        let (ast, input) = parse_extended(b"+!]![").unwrap();
+        assert!(matches!(ast[..], [AST::Add(1)]));
+        assert_eq!(input.as_deref(), Some(&b"]!["[..]));
+
+        // Loops are still open when the code ends
+        let errors = parse_extended(b"[+!]").unwrap_err();
+        assert_eq!(errors[0].kind().code(), "E001");
+    }
+
+    #[test]
+    fn extensions_off() {
+        // Comments, like any other character
+        let ast = parse(b"#,!.#").unwrap();
+        assert!(matches!(ast[..], [AST::Input, AST::Output]));
+        assert_eq!(
+            parse_flat(b"#,!.#").unwrap(),
+            [FlatAST::Input, FlatAST::Output]
+        );
+    }
+
+    #[test]
+    fn flat_round_trip() {
+        let code = b"+++[>++[->+>-<<]<-]>>.#[[-]+,]";
+        let ast = parse(code).unwrap();
+        let flat = parse_flat(code).unwrap();
+        assert_eq!(ast_to_flat(&ast), flat);
+        assert_eq!(ast_to_flat(&flat_to_ast(&flat)), flat);
+        assert_eq!(
+            format!("{:?}", flat_to_ast(&ast_to_flat(&ast))),
+            format!("{:?}", ast)
+        );
+    }
+
+    #[test]
+    fn flat_extensions() {
+        let (ast, input) = parse_extended(b"[#-]#!ab").unwrap();
+        let (flat, flat_input) = parse_flat_extended(b"[#-]#!ab").unwrap();
+        assert_eq!(ast_to_flat(&ast), flat);
+        assert_eq!(flat[1], FlatAST::Debug);
+        assert_eq!(input, flat_input);
+    }
+}
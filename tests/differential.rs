@@ -0,0 +1,152 @@
+//! Random programs, run before and after being written back as optimized
+//! brainfuck, which must behave the same
+
+use std::collections::HashMap;
+
+/// Most steps the original program is run for
+const MAX_STEPS: usize = 100_000;
+
+const PROGRAMS: usize = 2000;
+
+const INPUT: &[u8] = b"\x03\x00\x07\xff\x80";
+
+/// A xorshift generator, so failures can be reproduced
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Appends one of *chars* to *code*, one to *max* + 1 times
+    fn repeat(&mut self, code: &mut String, chars: &[char], max: usize) {
+        let c = chars[self.below(chars.len())];
+        for _ in 0..=self.below(max) {
+            code.push(c);
+        }
+    }
+}
+
+/// Appends code to *code*, with loops like those the optimizer handles
+/// specially, nested up to *depth* deep
+fn gen(rng: &mut Rng, code: &mut String, depth: usize) {
+    for _ in 0..rng.below(8) {
+        match rng.below(if depth == 0 { 6 } else { 10 }) {
+            0 | 1 => {
+                rng.repeat(code, &['+', '-', '>', '<'], 5);
+            }
+            2 => code.push('.'),
+            3 => code.push(','),
+            4 => code.push_str(["[-]", "[+]", "[---]"][rng.below(3)]),
+            5 => code.push_str(["[>]", "[<]", "[>>]", "[<<<]"][rng.below(4)]),
+            // A counter loop, copying to other cells
+            6 | 7 => {
+                code.push('[');
+                rng.repeat(code, &['-', '+'], 3);
+                let mut shift = 0i32;
+                for _ in 0..rng.below(3) {
+                    let offset = rng.below(5) as i32 - 2;
+                    let c = if offset > shift { '>' } else { '<' };
+                    code.extend(std::iter::repeat_n(
+                        c,
+                        (offset - shift).unsigned_abs() as usize,
+                    ));
+                    shift = offset;
+                    rng.repeat(code, &['+', '-'], 3);
+                }
+                let c = if shift > 0 { '<' } else { '>' };
+                code.extend(std::iter::repeat_n(c, shift.unsigned_abs() as usize));
+                code.push(']');
+            }
+            // Runs once
+            8 => {
+                code.push('[');
+                gen(rng, code, depth - 1);
+                code.push_str("[-]]");
+            }
+            _ => {
+                code.push('[');
+                gen(rng, code, depth - 1);
+                code.push(']');
+            }
+        }
+    }
+}
+
+/// Runs *code* with 8 bit cells, returning its output if it ends within
+/// *max_steps* steps
+fn run(code: &[u8], max_steps: usize) -> Option<Vec<u8>> {
+    let mut matching = vec![0; code.len()];
+    let mut loops = Vec::new();
+    for (i, c) in code.iter().enumerate() {
+        match c {
+            b'[' => loops.push(i),
+            b']' => {
+                let start = loops.pop().unwrap();
+                matching[start] = i;
+                matching[i] = start;
+            }
+            _ => {}
+        }
+    }
+
+    let mut tape = HashMap::<i64, u8>::new();
+    let mut cursor = 0i64;
+    let mut input = INPUT.iter();
+    let mut output = Vec::new();
+    let mut pc = 0;
+    for _ in 0..max_steps {
+        if pc == code.len() {
+            return Some(output);
+        }
+        let cell = tape.entry(cursor).or_insert(0);
+        match code[pc] {
+            b'+' => *cell = cell.wrapping_add(1),
+            b'-' => *cell = cell.wrapping_sub(1),
+            b'>' => cursor += 1,
+            b'<' => cursor -= 1,
+            b'.' => output.push(*cell),
+            b',' => *cell = input.next().cloned().unwrap_or(0),
+            b'[' if *cell == 0 => pc = matching[pc],
+            b']' if *cell != 0 => pc = matching[pc],
+            _ => {}
+        }
+        pc += 1;
+    }
+    None
+}
+
+#[test]
+fn brainfuck_backend() {
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+    let mut tested = 0;
+    for _ in 0..PROGRAMS {
+        let mut code = String::new();
+        gen(&mut rng, &mut code, 3);
+        let expected = match run(code.as_bytes(), MAX_STEPS) {
+            Some(output) => output,
+            None => continue,
+        };
+        tested += 1;
+
+        let ast = isbfc::parse(code.as_bytes()).unwrap();
+        let optimized = isbfc::optimize_to_brainfuck(&ast);
+        // Clearing a cell can take more steps than the loop it replaces
+        let output = run(optimized.as_bytes(), MAX_STEPS * 10);
+        assert_eq!(
+            output.as_ref(),
+            Some(&expected),
+            "{} written as {}",
+            code,
+            optimized
+        );
+    }
+    assert!(tested > PROGRAMS / 2, "only {} programs ended", tested);
+}
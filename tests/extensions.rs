@@ -0,0 +1,166 @@
+//! The `#` tape dump extension, which optimizations must not move code
+//! across
+
+use std::collections::HashMap;
+
+use isbfc::lir::{self, CellType, LVal, RVal, LIR};
+use isbfc::{MAX_LEVEL, OPTIMIZERS};
+
+/// Cells on each side of the dumped one that are compared
+const DUMP_CELLS: i32 = 3;
+
+const PROGRAMS: &[&str] = &[
+    "+#-#",
+    ">>+<<#-#",
+    "++[>+++<-]>#<#[-]>[<+>-]<#",
+    "+>++>+++<<#[>]#<[-]#",
+    "+++[#-]#",
+    "+[->+>+<<]#>[-<+>]#",
+];
+
+/// Runs *lir* with 8 bit cells and no input, returning the cells around
+/// each dumped one
+fn run(lir: &[LIR]) -> Vec<Vec<u8>> {
+    let lir = lir::flatten(lir);
+    let labels = (lir.iter().enumerate())
+        .filter_map(|(i, instr)| match instr {
+            LIR::Label(label) => Some((label.clone(), i)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut tape = HashMap::<i32, u8>::new();
+    let mut regs = HashMap::new();
+    let mut bufs = HashMap::new();
+    let mut cursor = 0;
+    let mut dumps = Vec::new();
+
+    let get = |tape: &HashMap<i32, u8>,
+               regs: &HashMap<u32, u8>,
+               bufs: &HashMap<_, Vec<u8>>,
+               cursor: i32,
+               val: &RVal| match val {
+        RVal::Reg(reg) => regs[reg],
+        RVal::Tape(offset) => tape.get(&(cursor + offset)).copied().unwrap_or(0),
+        RVal::Buf(buf, offset) => bufs[buf][*offset],
+        RVal::Immediate(value) => *value as u8,
+    };
+
+    let mut i = 0;
+    while i < lir.len() {
+        let value = |val| get(&tape, &regs, &bufs, cursor, val);
+        let (dest, result) = match &lir[i] {
+            LIR::Shift(offset) => {
+                cursor += offset;
+                (None, 0)
+            }
+            LIR::Add(dest, a, b) => (Some(dest), value(a).wrapping_add(value(b))),
+            LIR::Sub(dest, a, b) => (Some(dest), value(a).wrapping_sub(value(b))),
+            LIR::Mul(dest, a, b) => (Some(dest), value(a).wrapping_mul(value(b))),
+            LIR::Mov(dest, a) => (Some(dest), value(a)),
+            LIR::Select(dest, cond, a, b) => {
+                let result = if value(cond) != 0 { value(a) } else { value(b) };
+                (Some(dest), result)
+            }
+            LIR::Jp(label) => {
+                i = labels[label];
+                (None, 0)
+            }
+            LIR::Jz(cond, label) => {
+                if value(cond) == 0 {
+                    i = labels[label];
+                }
+                (None, 0)
+            }
+            LIR::Jnz(cond, label) => {
+                if value(cond) != 0 {
+                    i = labels[label];
+                }
+                (None, 0)
+            }
+            LIR::Scan(stride) => {
+                while tape.get(&cursor).copied().unwrap_or(0) != 0 {
+                    cursor += stride;
+                }
+                (None, 0)
+            }
+            LIR::DeclareBssBuf(buf, len) => {
+                bufs.insert(buf.clone(), vec![0; *len]);
+                (None, 0)
+            }
+            LIR::DeclareRodataBuf(buf, data) => {
+                bufs.insert(buf.clone(), data.clone());
+                (None, 0)
+            }
+            LIR::Input(..) => panic!("programs must not read input"),
+            LIR::DebugDump(offset) => {
+                let dumped = cursor + offset;
+                let cells = (dumped - DUMP_CELLS..=dumped + DUMP_CELLS)
+                    .map(|cell| tape.get(&cell).copied().unwrap_or(0))
+                    .collect();
+                dumps.push(cells);
+                (None, 0)
+            }
+            _ => (None, 0),
+        };
+        match dest {
+            Some(LVal::Reg(reg)) => {
+                regs.insert(*reg, result);
+            }
+            Some(LVal::Tape(offset)) => {
+                tape.insert(cursor + offset, result);
+            }
+            Some(LVal::Buf(buf, offset)) => {
+                bufs.get_mut(buf).unwrap()[*offset] = result;
+            }
+            None => {}
+        }
+        i += 1;
+    }
+    dumps
+}
+
+/// Dumps of *code*, run without optimizing it
+fn expected_dumps(code: &[u8]) -> Vec<Vec<u8>> {
+    let (ast, _) = isbfc::parse_extended(code).unwrap();
+    let optimizer = OPTIMIZERS["simple"];
+    run(&optimizer.optimize(&ast, &optimizer.passes_at(0), CellType::U8))
+}
+
+#[test]
+fn reference_dumps() {
+    assert_eq!(
+        expected_dumps(b"+>++<#>#"),
+        [[0, 0, 0, 1, 2, 0, 0], [0, 0, 1, 2, 0, 0, 0]]
+    );
+}
+
+#[test]
+fn dump_is_a_barrier() {
+    for code in PROGRAMS {
+        let expected = expected_dumps(code.as_bytes());
+
+        let (ast, _) = isbfc::parse_extended(code.as_bytes()).unwrap();
+        for (name, optimizer) in OPTIMIZERS.iter() {
+            for level in 0..=MAX_LEVEL {
+                let lir = optimizer.optimize(&ast, &optimizer.passes_at(level), CellType::U8);
+                assert_eq!(run(&lir), expected, "{} with {} at -O{}", code, name, level);
+            }
+        }
+    }
+}
+
+#[test]
+fn dump_is_a_barrier_in_brainfuck() {
+    for code in PROGRAMS {
+        let (ast, _) = isbfc::parse_extended(code.as_bytes()).unwrap();
+        let output = isbfc::optimize_to_brainfuck(&ast);
+        assert_eq!(
+            expected_dumps(output.as_bytes()),
+            expected_dumps(code.as_bytes()),
+            "{} written as {}",
+            code,
+            output
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Programs nested too deeply to be handled recursively
+
+use cranelift_codegen::ir::types::I8;
+use isbfc::codegen::cranelift::codegen_fn;
+use isbfc::lir::{self, CellType, OutputBuffering, LIR};
+use isbfc::{DEFAULT_STEP_BUDGET, MAX_LEVEL, OPTIMIZERS};
+
+const DEPTH: usize = 100_000;
+
+#[test]
+fn deep_nesting() {
+    // Input first, so only the compiler has to handle the nesting
+    let code = ",".to_string() + &"[>+".repeat(DEPTH) + &"-<]".repeat(DEPTH) + ".";
+    let ast = isbfc::parse(code.as_bytes()).unwrap();
+    let ast = isbfc::partial_eval(&ast, CellType::U8, DEFAULT_STEP_BUDGET).rest;
+    for (name, optimizer) in OPTIMIZERS.iter() {
+        for level in 0..=MAX_LEVEL {
+            let passes = optimizer.passes_at(level);
+            let lir = optimizer.optimize(&ast, &passes, CellType::U8);
+            let lir = lir::flatten(&lir::buffer_output(&lir, OutputBuffering::Full));
+            let loops = lir.iter().filter(|i| matches!(i, LIR::Jnz(..))).count();
+            assert_eq!(loops, DEPTH, "{} at -O{}", name, level);
+        }
+    }
+}
+
+#[test]
+fn deep_nesting_cranelift() {
+    // No I/O, which the cranelift backend doesn't generate yet
+    let code = "+".to_string() + &"[>+".repeat(DEPTH) + &"-<]".repeat(DEPTH);
+    let ast = isbfc::parse(code.as_bytes()).unwrap();
+    // Only the backend is being tested, so use the optimizer that leaves
+    // the simplest code
+    let optimizer = OPTIMIZERS["simple"];
+    let lir = optimizer.optimize(&ast, &optimizer.passes_at(0), CellType::U8);
+    let lir = lir::buffer_output(&lir, OutputBuffering::Full);
+    let func = codegen_fn(&lir, I8, 8192);
+    // A header, body and exit block for each loop, and the entry block
+    assert_eq!(func.layout.blocks().count(), 3 * DEPTH + 1);
+}